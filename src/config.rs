@@ -0,0 +1,545 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::NaiveDate;
+use clap::Parser;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing_subscriber::filter::LevelFilter;
+use traewelling_exporter::traewelling::client::DEFAULT_TRAEWELLING_BASE_URL;
+use url::Url;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:3000";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_SNAPSHOT_AGE: Duration = Duration::from_secs(300);
+const DEFAULT_RATE_LIMIT_THRESHOLD: u32 = 10;
+const DEFAULT_STATISTICS_INTERVAL: Duration = Duration::from_secs(300);
+/// Name of the instance configured by `api_url` and `token` when no `[[instances]]` are.
+const DEFAULT_INSTANCE_NAME: &str = "default";
+
+/// Every label the `journeys` gauge can be partitioned by.
+const JOURNEY_LABELS: [&str; 9] = [
+    "category",
+    "line_name",
+    "number",
+    "user_id",
+    "user_name",
+    "origin",
+    "destination",
+    "event_id",
+    "event_name",
+];
+const LOW_CARDINALITY_JOURNEY_LABELS: [&str; 2] = ["category", "line_name"];
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(short, long, env = "TRAEWELLING_EXPORTER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration, print it and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(flatten)]
+    pub options: Options,
+}
+
+/// Settings which can be given both as flags/environment variables and in the
+/// configuration file. Flags and environment variables take precedence.
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Address the HTTP server listens on [default: 0.0.0.0:3000]
+    #[arg(long, env = "LISTEN_ADDRESS")]
+    pub listen_address: Option<SocketAddr>,
+
+    /// Base URL of the Traewelling API [default: https://traewelling.de/api/v1]
+    #[arg(long, env = "TRAEWELLING_API")]
+    pub api_url: Option<Url>,
+
+    /// Token used to authenticate against the Traewelling API
+    #[arg(long, env = "TRAEWELLING_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// File to read the Traewelling API token from
+    #[arg(long, env = "TRAEWELLING_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// Seconds after which a request to Traewelling is aborted
+    #[arg(long, env = "TRAEWELLING_REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+
+    /// Seconds after which connecting to Traewelling is aborted
+    #[arg(long, env = "TRAEWELLING_CONNECT_TIMEOUT")]
+    pub connect_timeout: Option<u64>,
+
+    /// Proxy used for all requests to Traewelling
+    #[arg(long, env = "TRAEWELLING_PROXY")]
+    pub proxy: Option<Url>,
+
+    /// PEM file with an additional root certificate to trust
+    #[arg(long, env = "TRAEWELLING_CA_CERTIFICATE")]
+    pub ca_certificate: Option<PathBuf>,
+
+    /// Seconds between two polls of the active statuses [default: 30]
+    #[arg(long, env = "TRAEWELLING_POLL_INTERVAL")]
+    pub poll_interval: Option<u64>,
+
+    /// Seconds after which the last fetched journeys are no longer exported when
+    /// polling fails [default: 300]
+    #[arg(long, env = "TRAEWELLING_MAX_SNAPSHOT_AGE")]
+    pub max_snapshot_age: Option<u64>,
+
+    /// Remaining rate limit below which polling slows down [default: 10]
+    #[arg(long, env = "TRAEWELLING_RATE_LIMIT_THRESHOLD")]
+    pub rate_limit_threshold: Option<u32>,
+
+    /// Export the lifetime statistics of the user the token belongs to
+    #[arg(
+        long,
+        env = "TRAEWELLING_PERSONAL",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub personal: Option<bool>,
+
+    /// Export statistics of the current and upcoming events
+    #[arg(
+        long,
+        env = "TRAEWELLING_EVENTS",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub events: Option<bool>,
+
+    /// Export the given number of top users of the leaderboards [default: disabled]
+    #[arg(long, env = "TRAEWELLING_LEADERBOARD_SIZE")]
+    pub leaderboard_size: Option<usize>,
+
+    /// Replace usernames in the leaderboard metrics by a hash of them
+    #[arg(
+        long,
+        env = "TRAEWELLING_HASH_USERNAMES",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub hash_usernames: Option<bool>,
+
+    /// Secret key the usernames are hashed with, required by --hash-usernames
+    #[arg(long, env = "TRAEWELLING_USERNAME_HASH_KEY", hide_env_values = true)]
+    pub username_hash_key: Option<String>,
+
+    /// Seconds between two polls of statistics, which change rarely [default: 300]
+    #[arg(long, env = "TRAEWELLING_STATISTICS_INTERVAL")]
+    pub statistics_interval: Option<u64>,
+
+    /// Maximum log level (off, error, warn, info, debug, trace) [default: info]
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Labels of the journeys gauge: `all`, `low_cardinality` or a comma separated list
+    /// [default: low_cardinality]
+    #[arg(long, env = "TRAEWELLING_JOURNEY_LABELS")]
+    pub journey_labels: Option<String>,
+
+    /// SQLite database recording every check-in seen [default: disabled]
+    #[arg(long, env = "TRAEWELLING_DATABASE")]
+    pub database: Option<PathBuf>,
+
+    /// Date from which on the recorded check-ins are counted [default: all]
+    #[arg(long, env = "TRAEWELLING_HISTORY_START")]
+    pub history_start: Option<NaiveDate>,
+
+    /// Traewelling instances to poll, only configurable in the configuration file
+    #[arg(skip)]
+    pub instances: Vec<InstanceOptions>,
+
+    /// Modules of the `/probe` endpoint, only configurable in the configuration file
+    #[arg(skip)]
+    pub modules: BTreeMap<String, ModuleOptions>,
+}
+
+/// A Traewelling instance from an `[[instances]]` table of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceOptions {
+    pub name: String,
+    pub api_url: Url,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+}
+
+/// A module from a `[modules.<name>]` table of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModuleOptions {
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    /// Targets the module may probe, required if it has a token.
+    #[serde(default)]
+    pub targets: Vec<Url>,
+}
+
+impl Options {
+    /// Fills every setting which isn't set in `self` from `other`.
+    fn or(self, other: Options) -> Options {
+        Options {
+            listen_address: self.listen_address.or(other.listen_address),
+            api_url: self.api_url.or(other.api_url),
+            token: self.token.or(other.token),
+            token_file: self.token_file.or(other.token_file),
+            request_timeout: self.request_timeout.or(other.request_timeout),
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            proxy: self.proxy.or(other.proxy),
+            ca_certificate: self.ca_certificate.or(other.ca_certificate),
+            poll_interval: self.poll_interval.or(other.poll_interval),
+            max_snapshot_age: self.max_snapshot_age.or(other.max_snapshot_age),
+            rate_limit_threshold: self.rate_limit_threshold.or(other.rate_limit_threshold),
+            personal: self.personal.or(other.personal),
+            events: self.events.or(other.events),
+            leaderboard_size: self.leaderboard_size.or(other.leaderboard_size),
+            hash_usernames: self.hash_usernames.or(other.hash_usernames),
+            username_hash_key: self.username_hash_key.or(other.username_hash_key),
+            statistics_interval: self.statistics_interval.or(other.statistics_interval),
+            log_level: self.log_level.or(other.log_level),
+            journey_labels: self.journey_labels.or(other.journey_labels),
+            database: self.database.or(other.database),
+            history_start: self.history_start.or(other.history_start),
+            instances: if self.instances.is_empty() {
+                other.instances
+            } else {
+                self.instances
+            },
+            modules: if self.modules.is_empty() {
+                other.modules
+            } else {
+                self.modules
+            },
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid configuration file {}: {source}", path.display())]
+    InvalidFile {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Only one of token and token_file may be set")]
+    ConflictingToken,
+    #[error("The personal mode requires a token")]
+    MissingToken,
+    #[error("Hashing usernames requires a username_hash_key")]
+    MissingUsernameHashKey,
+    #[error("api_url, token and token_file can't be combined with instances")]
+    AmbiguousInstance,
+    #[error("Duplicate instance name: {0}")]
+    DuplicateInstance(String),
+    #[error("Module {0} has a token and thus must restrict its targets")]
+    UnrestrictedModule(String),
+    #[error("Invalid log level: {0}")]
+    InvalidLogLevel(String),
+    #[error("Unknown journey label: {0}")]
+    UnknownJourneyLabel(String),
+    #[error("Duplicate journey label: {0}")]
+    DuplicateJourneyLabel(String),
+    #[error("At least one journey label is required")]
+    NoJourneyLabels,
+}
+
+/// A Traewelling instance which is polled independently of the others.
+#[derive(Debug)]
+pub struct InstanceConfig {
+    /// Value of the `instance` label of all metrics of this instance.
+    pub name: String,
+    pub api_url: Url,
+    pub token: Option<String>,
+}
+
+/// Settings of a probe, selected by the `module` parameter of the `/probe` endpoint.
+#[derive(Debug)]
+pub struct ModuleConfig {
+    pub token: Option<String>,
+    /// Targets the module may probe, any if empty.
+    pub targets: Vec<Url>,
+}
+
+impl ModuleConfig {
+    pub fn allows(&self, target: &Url) -> bool {
+        self.targets.is_empty() || self.targets.contains(target)
+    }
+}
+
+/// The effective configuration of the exporter.
+#[derive(Debug)]
+pub struct Config {
+    pub listen_address: SocketAddr,
+    pub instances: Vec<InstanceConfig>,
+    pub modules: BTreeMap<String, ModuleConfig>,
+    pub request_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub proxy: Option<Url>,
+    pub ca_certificate: Option<PathBuf>,
+    pub poll_interval: Duration,
+    pub max_snapshot_age: Duration,
+    pub rate_limit_threshold: u32,
+    pub personal: bool,
+    pub events: bool,
+    pub leaderboard_size: Option<usize>,
+    /// Key the usernames of the leaderboards are hashed with, `None` if they aren't.
+    pub username_hash_key: Option<String>,
+    pub statistics_interval: Duration,
+    pub log_level: LevelFilter,
+    pub journey_labels: Vec<&'static str>,
+    pub database: Option<PathBuf>,
+    pub history_start: Option<NaiveDate>,
+}
+
+impl Config {
+    /// Resolves the configuration from the given options, falling back to the
+    /// configuration file and the defaults.
+    pub fn load(file: Option<&Path>, options: Options) -> Result<Config, ConfigError> {
+        let options = match file {
+            Some(path) => options.or(read_file(path)?),
+            None => options,
+        };
+        let instances = if options.instances.is_empty() {
+            vec![InstanceConfig {
+                name: DEFAULT_INSTANCE_NAME.to_string(),
+                api_url: options
+                    .api_url
+                    .unwrap_or_else(|| Url::parse(DEFAULT_TRAEWELLING_BASE_URL).unwrap()),
+                token: read_token(options.token, options.token_file)?,
+            }]
+        } else if options.api_url.is_some()
+            || options.token.is_some()
+            || options.token_file.is_some()
+        {
+            return Err(ConfigError::AmbiguousInstance);
+        } else {
+            let mut instances: Vec<InstanceConfig> = Vec::new();
+            for instance in options.instances {
+                if instances.iter().any(|known| known.name == instance.name) {
+                    return Err(ConfigError::DuplicateInstance(instance.name));
+                }
+                instances.push(InstanceConfig {
+                    name: instance.name,
+                    api_url: instance.api_url,
+                    token: read_token(instance.token, instance.token_file)?,
+                });
+            }
+            instances
+        };
+        let modules = options
+            .modules
+            .into_iter()
+            .map(|(name, module)| {
+                let token = read_token(module.token, module.token_file)?;
+                // Otherwise anyone could have the token sent to a server of theirs
+                if token.is_some() && module.targets.is_empty() {
+                    return Err(ConfigError::UnrestrictedModule(name));
+                }
+                let targets = module.targets;
+                Ok((name, ModuleConfig { token, targets }))
+            })
+            .collect::<Result<_, ConfigError>>()?;
+        let personal = options.personal.unwrap_or_default();
+        if personal && instances.iter().all(|instance| instance.token.is_none()) {
+            return Err(ConfigError::MissingToken);
+        }
+        let username_hash_key = if options.hash_usernames.unwrap_or_default() {
+            Some(
+                options
+                    .username_hash_key
+                    .ok_or(ConfigError::MissingUsernameHashKey)?,
+            )
+        } else {
+            None
+        };
+        let log_level = match options.log_level {
+            Some(level) => level
+                .parse()
+                .map_err(|_| ConfigError::InvalidLogLevel(level))?,
+            None => LevelFilter::INFO,
+        };
+        let journey_labels = match options.journey_labels {
+            Some(labels) => parse_journey_labels(&labels)?,
+            None => LOW_CARDINALITY_JOURNEY_LABELS.to_vec(),
+        };
+        Ok(Config {
+            listen_address: options
+                .listen_address
+                .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.parse().unwrap()),
+            instances,
+            modules,
+            request_timeout: options.request_timeout.map(Duration::from_secs),
+            connect_timeout: options.connect_timeout.map(Duration::from_secs),
+            proxy: options.proxy,
+            ca_certificate: options.ca_certificate,
+            poll_interval: options
+                .poll_interval
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_POLL_INTERVAL),
+            max_snapshot_age: options
+                .max_snapshot_age
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_MAX_SNAPSHOT_AGE),
+            rate_limit_threshold: options
+                .rate_limit_threshold
+                .unwrap_or(DEFAULT_RATE_LIMIT_THRESHOLD),
+            personal,
+            events: options.events.unwrap_or_default(),
+            leaderboard_size: options.leaderboard_size,
+            username_hash_key,
+            statistics_interval: options
+                .statistics_interval
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_STATISTICS_INTERVAL),
+            log_level,
+            journey_labels,
+            database: options.database,
+            history_start: options.history_start,
+        })
+    }
+}
+
+/// Prints the configuration in the format of the configuration file, without the tokens.
+impl Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "listen_address = \"{}\"", self.listen_address)?;
+        if let Some(request_timeout) = self.request_timeout {
+            writeln!(f, "request_timeout = {}", request_timeout.as_secs())?;
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            writeln!(f, "connect_timeout = {}", connect_timeout.as_secs())?;
+        }
+        if let Some(proxy) = &self.proxy {
+            writeln!(f, "proxy = \"{proxy}\"")?;
+        }
+        if let Some(ca_certificate) = &self.ca_certificate {
+            writeln!(
+                f,
+                "ca_certificate = {:?}",
+                ca_certificate.display().to_string()
+            )?;
+        }
+        writeln!(f, "poll_interval = {}", self.poll_interval.as_secs())?;
+        writeln!(f, "max_snapshot_age = {}", self.max_snapshot_age.as_secs())?;
+        writeln!(f, "rate_limit_threshold = {}", self.rate_limit_threshold)?;
+        writeln!(f, "personal = {}", self.personal)?;
+        writeln!(f, "events = {}", self.events)?;
+        if let Some(leaderboard_size) = self.leaderboard_size {
+            writeln!(f, "leaderboard_size = {leaderboard_size}")?;
+        }
+        writeln!(f, "hash_usernames = {}", self.username_hash_key.is_some())?;
+        if self.username_hash_key.is_some() {
+            writeln!(f, "# username_hash_key = \"<redacted>\"")?;
+        }
+        writeln!(
+            f,
+            "statistics_interval = {}",
+            self.statistics_interval.as_secs()
+        )?;
+        writeln!(f, "log_level = \"{}\"", self.log_level)?;
+        write!(f, "journey_labels = \"{}\"", self.journey_labels.join(","))?;
+        if let Some(database) = &self.database {
+            write!(f, "\ndatabase = {:?}", database.display().to_string())?;
+        }
+        if let Some(history_start) = self.history_start {
+            write!(f, "\nhistory_start = \"{history_start}\"")?;
+        }
+        for instance in &self.instances {
+            write!(f, "\n\n[[instances]]")?;
+            write!(f, "\nname = {:?}", instance.name)?;
+            write!(f, "\napi_url = \"{}\"", instance.api_url)?;
+            if instance.token.is_some() {
+                write!(f, "\n# token = \"<redacted>\"")?;
+            }
+        }
+        for (name, module) in &self.modules {
+            write!(f, "\n\n[modules.{name:?}]")?;
+            if module.token.is_some() {
+                write!(f, "\n# token = \"<redacted>\"")?;
+            }
+            if !module.targets.is_empty() {
+                let targets: Vec<_> = module
+                    .targets
+                    .iter()
+                    .map(|target| format!("\"{target}\""))
+                    .collect();
+                write!(f, "\ntargets = [{}]", targets.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Takes the token either as is or from the given file.
+fn read_token(token: Option<String>, file: Option<PathBuf>) -> Result<Option<String>, ConfigError> {
+    match (token, file) {
+        (Some(_), Some(_)) => Err(ConfigError::ConflictingToken),
+        (Some(token), None) => Ok(Some(token)),
+        (None, Some(path)) => Ok(Some(
+            fs::read_to_string(&path)
+                .map_err(|source| ConfigError::Io { path, source })?
+                .trim()
+                .to_string(),
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Options, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    toml::from_str(&content).map_err(|source| ConfigError::InvalidFile {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Parses either a preset (`all`, `low_cardinality`) or a non-empty comma separated
+/// list of distinct [`JOURNEY_LABELS`].
+fn parse_journey_labels(value: &str) -> Result<Vec<&'static str>, ConfigError> {
+    let labels = match value.trim() {
+        "all" => return Ok(JOURNEY_LABELS.to_vec()),
+        "low_cardinality" => return Ok(LOW_CARDINALITY_JOURNEY_LABELS.to_vec()),
+        labels => labels,
+    };
+    let mut parsed = Vec::new();
+    for label in labels
+        .split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+    {
+        let known = JOURNEY_LABELS
+            .iter()
+            .find(|known| **known == label)
+            .copied()
+            .ok_or_else(|| ConfigError::UnknownJourneyLabel(label.to_string()))?;
+        if parsed.contains(&known) {
+            return Err(ConfigError::DuplicateJourneyLabel(label.to_string()));
+        }
+        parsed.push(known);
+    }
+    if parsed.is_empty() {
+        return Err(ConfigError::NoJourneyLabels);
+    }
+    Ok(parsed)
+}
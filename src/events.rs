@@ -0,0 +1,180 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Utc;
+use futures::TryStreamExt;
+use prometheus::{
+    register_gauge_vec_with_registry, register_int_gauge_vec_with_registry, GaugeVec, IntGaugeVec,
+    Registry,
+};
+use tokio::sync::RwLock;
+use traewelling_exporter::traewelling::client::{EventDetails, Status, TraewellingClient};
+
+use crate::{poll_every, Snapshot};
+
+/// Metrics about the current and upcoming events on Traewelling.
+#[derive(Clone)]
+pub struct EventMetrics {
+    info: IntGaugeVec,
+    begin: GaugeVec,
+    end: GaugeVec,
+    active_travellers: IntGaugeVec,
+    distance: GaugeVec,
+    duration: GaugeVec,
+}
+
+impl EventMetrics {
+    pub fn register(registry: &Registry) -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            info: register_int_gauge_vec_with_registry!(
+                "event_info",
+                "Current and upcoming events",
+                &["event_id", "event_name", "host", "station"],
+                registry
+            )?,
+            begin: register_gauge_vec_with_registry!(
+                "event_begin_timestamp_seconds",
+                "Unix timestamp of the begin of the event",
+                &["event_id", "event_name"],
+                registry
+            )?,
+            end: register_gauge_vec_with_registry!(
+                "event_end_timestamp_seconds",
+                "Unix timestamp of the end of the event",
+                &["event_id", "event_name"],
+                registry
+            )?,
+            active_travellers: register_int_gauge_vec_with_registry!(
+                "event_active_travellers",
+                "Users currently travelling to the event",
+                &["event_id", "event_name"],
+                registry
+            )?,
+            distance: register_gauge_vec_with_registry!(
+                "event_distance_meters",
+                "Total distance travelled to the event",
+                &["event_id", "event_name"],
+                registry
+            )?,
+            duration: register_gauge_vec_with_registry!(
+                "event_duration_seconds",
+                "Total duration travelled to the event",
+                &["event_id", "event_name"],
+                registry
+            )?,
+        })
+    }
+
+    fn reset(&self) {
+        self.info.reset();
+        self.begin.reset();
+        self.end.reset();
+        self.active_travellers.reset();
+        self.distance.reset();
+        self.duration.reset();
+    }
+}
+
+/// Polls the events, taking their active travellers from the `snapshot` of the
+/// active statuses.
+pub async fn poll_events(
+    client: TraewellingClient,
+    metrics: EventMetrics,
+    snapshot: Arc<RwLock<Option<Snapshot>>>,
+    poll_interval: Duration,
+) {
+    poll_every(poll_interval, "events", || {
+        record_events(&client, &metrics, &snapshot)
+    })
+    .await
+}
+
+async fn record_events(
+    client: &TraewellingClient,
+    metrics: &EventMetrics,
+    snapshot: &RwLock<Option<Snapshot>>,
+) -> Result<(), traewelling_exporter::Error> {
+    let events: Vec<EventDetails> = client.events().stream_events().try_collect().await?;
+    tracing::trace!("Observing {} events", events.len());
+
+    // Fetch everything before touching the metrics, so they never miss events while
+    // we are waiting for Traewelling or when a request fails
+    let mut details = Vec::with_capacity(events.len());
+    for event in events {
+        let statistics = client.events().get_event_statistics(&event.slug).await?;
+        details.push((event, statistics));
+    }
+    let active_travellers = count_active_travellers(snapshot.read().await.as_ref());
+
+    metrics.reset();
+    for (event, statistics) in details {
+        let event_id = event.id.to_string();
+        let labels = [event_id.as_str(), event.name.as_str()];
+        metrics
+            .info
+            .with_label_values(&[
+                event_id.as_str(),
+                event.name.as_str(),
+                event.host.as_deref().unwrap_or_default(),
+                event
+                    .station
+                    .as_ref()
+                    .map(|station| station.name.as_str())
+                    .unwrap_or_default(),
+            ])
+            .set(1);
+        metrics
+            .begin
+            .with_label_values(&labels)
+            .set(event.begin.timestamp() as f64);
+        metrics
+            .end
+            .with_label_values(&labels)
+            .set(event.end.timestamp() as f64);
+        metrics
+            .distance
+            .with_label_values(&labels)
+            .set(statistics.train_distance as f64);
+        metrics
+            .duration
+            .with_label_values(&labels)
+            .set(statistics.train_duration_seconds() as f64);
+        metrics.active_travellers.with_label_values(&labels).set(
+            active_travellers
+                .get(&event.id)
+                .copied()
+                .unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
+
+/// Counts the distinct users per event who are currently travelling to it.
+fn count_active_travellers(snapshot: Option<&Snapshot>) -> HashMap<i32, i64> {
+    let mut travellers: HashMap<i32, HashSet<i32>> = HashMap::new();
+    let statuses = snapshot.into_iter().flat_map(|snapshot| &snapshot.statuses);
+    for status in statuses.filter(|status| is_travelling(status)) {
+        if let Some(event) = &status.event {
+            travellers.entry(event.id).or_default().insert(status.user);
+        }
+    }
+    travellers
+        .into_iter()
+        .map(|(event, users)| (event, users.len() as i64))
+        .collect()
+}
+
+/// Whether the journey of the status has departed but not arrived yet.
+fn is_travelling(status: &Status) -> bool {
+    let now = Utc::now();
+    match (
+        status.train.origin.departure,
+        status.train.destination.arrival,
+    ) {
+        (Some(departure), Some(arrival)) => departure <= now && now <= arrival,
+        _ => false,
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use prometheus::{register_int_counter_vec_with_registry, IntCounterVec, Registry};
+use rusqlite::{params, Connection};
+use traewelling_exporter::traewelling::client::Status;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS statuses (
+        instance TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        username TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        category TEXT NOT NULL,
+        distance INTEGER NOT NULL,
+        duration_seconds INTEGER NOT NULL,
+        points INTEGER NOT NULL,
+        train TEXT NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        PRIMARY KEY (instance, id)
+    );
+    CREATE INDEX IF NOT EXISTS statuses_created_at ON statuses (instance, created_at);
+";
+const UPSERT_STATUS: &str = "
+    INSERT INTO statuses (
+        instance, id, user_id, username, created_at, category, distance, duration_seconds, points,
+        train, first_seen, last_seen
+    )
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11)
+    ON CONFLICT (instance, id) DO UPDATE SET
+        category = excluded.category,
+        distance = excluded.distance,
+        duration_seconds = excluded.duration_seconds,
+        points = excluded.points,
+        train = excluded.train,
+        last_seen = excluded.last_seen
+";
+const SELECT_TOTALS: &str = "
+    SELECT category, COUNT(*), SUM(distance), SUM(duration_seconds)
+    FROM statuses
+    WHERE instance = ?1 AND created_at >= ?2
+    GROUP BY category
+";
+
+/// SQLite database recording every status seen, so that check-ins are still
+/// counted after they vanished from the active statuses.
+#[derive(Clone)]
+pub struct Store {
+    connection: Arc<Mutex<Connection>>,
+}
+
+/// Sums of the recorded check-ins of a category.
+struct CategoryTotals {
+    category: String,
+    checkins: i64,
+    distance: i64,
+    duration: i64,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Inserts the statuses which weren't seen before and updates the others.
+    fn record(
+        &self,
+        instance: &str,
+        statuses: &[Status],
+        now: DateTime<Utc>,
+    ) -> Result<(), rusqlite::Error> {
+        let mut connection = self.connection.lock().expect("Store lock poisoned");
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(UPSERT_STATUS)?;
+            for status in statuses {
+                let train = serde_json::to_string(&status.train)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                statement.execute(params![
+                    instance,
+                    status.id,
+                    status.user,
+                    status.username,
+                    status.created_at.timestamp(),
+                    status.train.category,
+                    status.train.distance,
+                    status.train.duration_seconds(),
+                    status.train.points,
+                    train,
+                    now.timestamp(),
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Sums up the check-ins created since the given time per category.
+    fn totals(
+        &self,
+        instance: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CategoryTotals>, rusqlite::Error> {
+        let connection = self.connection.lock().expect("Store lock poisoned");
+        let mut statement = connection.prepare_cached(SELECT_TOTALS)?;
+        let since = since.map_or(0, |since| since.timestamp());
+        let totals = statement.query_map(params![instance, since], |row| {
+            Ok(CategoryTotals {
+                category: row.get(0)?,
+                checkins: row.get(1)?,
+                distance: row.get(2)?,
+                duration: row.get(3)?,
+            })
+        })?;
+        totals.collect()
+    }
+}
+
+/// Records the statuses of an instance in the [`Store`] and exports the totals of
+/// all check-ins recorded so far.
+pub struct History {
+    store: Store,
+    instance: String,
+    since: Option<DateTime<Utc>>,
+    checkins: IntCounterVec,
+    distance: IntCounterVec,
+    duration: IntCounterVec,
+}
+
+impl History {
+    pub fn register(
+        store: Store,
+        instance: String,
+        since: Option<DateTime<Utc>>,
+        registry: &Registry,
+    ) -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            store,
+            instance,
+            since,
+            checkins: register_int_counter_vec_with_registry!(
+                "history_checkins_total",
+                "Check-ins recorded in the history",
+                &["category"],
+                registry
+            )?,
+            distance: register_int_counter_vec_with_registry!(
+                "history_distance_meters_total",
+                "Distance of the check-ins recorded in the history",
+                &["category"],
+                registry
+            )?,
+            duration: register_int_counter_vec_with_registry!(
+                "history_duration_seconds_total",
+                "Duration of the check-ins recorded in the history",
+                &["category"],
+                registry
+            )?,
+        })
+    }
+
+    pub fn record(&self, statuses: &[Status]) {
+        let result = tokio::task::block_in_place(|| {
+            self.store.record(&self.instance, statuses, Utc::now())?;
+            self.store.totals(&self.instance, self.since)
+        });
+        match result {
+            Ok(totals) => {
+                for totals in totals {
+                    let category = totals.category.as_str();
+                    advance(&self.checkins, category, totals.checkins);
+                    advance(&self.distance, category, totals.distance);
+                    advance(&self.duration, category, totals.duration);
+                }
+            }
+            Err(e) => tracing::error!("Failed to record statuses in the history: {}", e),
+        }
+    }
+}
+
+/// Raises the counter to the given total. Totals may shrink when a journey is
+/// shortened afterwards, which the counter ignores.
+fn advance(counter: &IntCounterVec, category: &str, total: i64) {
+    let counter = counter.with_label_values(&[category]);
+    let total = u64::try_from(total).unwrap_or_default();
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use chrono::{Datelike, Utc};
+use hmac::{Hmac, Mac};
+use prometheus::{
+    register_gauge_vec_with_registry, register_int_gauge_vec_with_registry, GaugeVec, IntGaugeVec,
+    Registry,
+};
+use sha2::Sha256;
+use traewelling_exporter::traewelling::client::{LeaderboardEntry, TraewellingClient};
+
+use crate::poll_every;
+
+/// Metrics about the top users of the Traewelling leaderboards.
+#[derive(Clone)]
+pub struct LeaderboardMetrics {
+    points: IntGaugeVec,
+    distance: GaugeVec,
+    duration: GaugeVec,
+}
+
+impl LeaderboardMetrics {
+    pub fn register(registry: &Registry) -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            points: register_int_gauge_vec_with_registry!(
+                "leaderboard_points",
+                "Points of the top users of a leaderboard",
+                &["leaderboard", "username"],
+                registry
+            )?,
+            distance: register_gauge_vec_with_registry!(
+                "leaderboard_distance_meters",
+                "Distance travelled by the top users of a leaderboard",
+                &["leaderboard", "username"],
+                registry
+            )?,
+            duration: register_gauge_vec_with_registry!(
+                "leaderboard_duration_seconds",
+                "Duration travelled by the top users of a leaderboard",
+                &["leaderboard", "username"],
+                registry
+            )?,
+        })
+    }
+}
+
+/// Which users of the leaderboards are exported.
+#[derive(Clone)]
+pub struct LeaderboardOptions {
+    /// Number of users exported per leaderboard.
+    pub size: usize,
+    /// Replaces usernames by a hash of them keyed with this secret.
+    pub username_hash_key: Option<String>,
+    /// Includes the leaderboard of the authenticated user and the users they follow.
+    pub friends: bool,
+}
+
+pub async fn poll_leaderboards(
+    client: TraewellingClient,
+    metrics: LeaderboardMetrics,
+    options: LeaderboardOptions,
+    poll_interval: Duration,
+) {
+    poll_every(poll_interval, "leaderboards", || {
+        record_leaderboards(&client, &metrics, &options)
+    })
+    .await
+}
+
+async fn record_leaderboards(
+    client: &TraewellingClient,
+    metrics: &LeaderboardMetrics,
+    options: &LeaderboardOptions,
+) -> Result<(), traewelling_exporter::Error> {
+    let leaderboards = client.leaderboards();
+    let today = Utc::now().date_naive();
+    let mut results = vec![
+        ("global", leaderboards.get_leaderboard().await?),
+        ("distance", leaderboards.get_distance_leaderboard().await?),
+        (
+            "monthly",
+            leaderboards
+                .get_monthly_leaderboard(today.year(), today.month())
+                .await?,
+        ),
+    ];
+    if options.friends {
+        results.push(("friends", leaderboards.get_friends_leaderboard().await?));
+    }
+
+    metrics.points.reset();
+    metrics.distance.reset();
+    metrics.duration.reset();
+    for (leaderboard, entries) in results {
+        record_leaderboard(leaderboard, &entries, metrics, options);
+    }
+    Ok(())
+}
+
+fn record_leaderboard(
+    leaderboard: &str,
+    entries: &[LeaderboardEntry],
+    metrics: &LeaderboardMetrics,
+    options: &LeaderboardOptions,
+) {
+    for entry in entries.iter().take(options.size) {
+        let username = match &options.username_hash_key {
+            Some(key) => hash_username(key, &entry.username),
+            None => entry.username.clone(),
+        };
+        let labels = [leaderboard, username.as_str()];
+        metrics.points.with_label_values(&labels).set(entry.points);
+        metrics
+            .distance
+            .with_label_values(&labels)
+            .set(entry.train_distance as f64);
+        metrics
+            .duration
+            .with_label_values(&labels)
+            .set(entry.train_duration_seconds() as f64);
+    }
+}
+
+/// A stable pseudonym for the username, so users can be followed over time without
+/// exposing who they are. The usernames are public on the leaderboards, so without
+/// the secret key anyone could hash them and map the pseudonyms back.
+fn hash_username(key: &str, username: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(username.as_bytes());
+    let hash = format!("{:x}", mac.finalize().into_bytes());
+    hash[..16].to_string()
+}
@@ -1,14 +1,33 @@
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
 use reqwest::StatusCode;
+use serde::Deserialize;
 use thiserror::Error;
 
 pub mod traewelling {
     pub mod client {
-        use std::env;
+        use std::{
+            collections::HashSet,
+            env, future,
+            sync::{Arc, Mutex},
+            time::{Duration, Instant},
+        };
 
-        use chrono::{DateTime, FixedOffset};
-        pub use reqwest::Client;
-        use reqwest::Url;
-        use serde::{Deserialize, Serialize};
+        use chrono::{DateTime, FixedOffset, Utc};
+        use futures::{stream, Stream, TryStreamExt};
+        use rand::Rng;
+        use reqwest::{
+            header::{HeaderMap, RETRY_AFTER},
+            IntoUrl, RequestBuilder, StatusCode, Url,
+        };
+        pub use reqwest::{Certificate, Client, Proxy};
+        use serde::{
+            de::{DeserializeOwned, IgnoredAny},
+            Deserialize, Serialize,
+        };
 
         use crate::Error;
 
@@ -17,9 +36,123 @@ pub mod traewelling {
             base_url: Url,
             client: Client,
             token: Option<String>,
+            retry_policy: RetryPolicy,
+            rate_limit: Arc<Mutex<Option<RateLimit>>>,
+            request_observer: Option<RequestObserver>,
+        }
+
+        /// A single HTTP request sent to Traewelling, retries are observed one by one.
+        pub struct RequestAttempt<'a> {
+            /// Time from sending the request until its response was read.
+            pub duration: Duration,
+            /// The error the request failed with, if it did.
+            pub error: Option<&'a Error>,
+        }
+
+        /// Called for every [`RequestAttempt`], e.g. to collect metrics.
+        pub type RequestObserver = Arc<dyn Fn(&RequestAttempt<'_>) + Send + Sync>;
+
+        /// The rate limit state reported by the last response of Traewelling.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub struct RateLimit {
+            /// Requests allowed in the current window.
+            pub limit: u32,
+            /// Requests left in the current window.
+            pub remaining: u32,
+        }
+
+        impl RateLimit {
+            fn from_headers(headers: &HeaderMap) -> Option<RateLimit> {
+                let header =
+                    |name: &str| -> Option<u32> { headers.get(name)?.to_str().ok()?.parse().ok() };
+                Some(RateLimit {
+                    limit: header("x-ratelimit-limit")?,
+                    remaining: header("x-ratelimit-remaining")?,
+                })
+            }
+        }
+
+        /// Controls how failed requests are retried.
+        #[derive(Clone, Debug)]
+        pub struct RetryPolicy {
+            /// Total number of attempts including the first request, `1` disables retries.
+            pub max_attempts: u32,
+            /// Delay before the first retry, doubled for every further retry.
+            pub backoff_base: Duration,
+            /// Upper bound of the delay between two attempts.
+            pub max_backoff: Duration,
+            /// Randomizes the delay between zero and the exponential backoff.
+            pub jitter: bool,
+            /// Status codes which are retried, connection errors and timeouts are always retried.
+            pub retryable_status_codes: Vec<StatusCode>,
+            /// Waits as long as the `Retry-After` header asks for. Requests aren't retried if
+            /// that's longer than `max_backoff`.
+            pub respect_retry_after: bool,
+        }
+
+        impl Default for RetryPolicy {
+            fn default() -> Self {
+                Self {
+                    max_attempts: 3,
+                    backoff_base: Duration::from_millis(500),
+                    max_backoff: Duration::from_secs(30),
+                    jitter: true,
+                    retryable_status_codes: vec![
+                        StatusCode::TOO_MANY_REQUESTS,
+                        StatusCode::BAD_GATEWAY,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        StatusCode::GATEWAY_TIMEOUT,
+                    ],
+                    respect_retry_after: true,
+                }
+            }
+        }
+
+        impl RetryPolicy {
+            /// A policy which never retries.
+            pub fn disabled() -> Self {
+                Self {
+                    max_attempts: 1,
+                    ..Self::default()
+                }
+            }
+
+            /// The delay before retrying after the given failed attempt, or `None` if the
+            /// request shouldn't be retried.
+            fn retry_delay(&self, attempt: u32, error: &Error) -> Option<Duration> {
+                if attempt >= self.max_attempts {
+                    return None;
+                }
+                let retryable = match error {
+                    Error::Reqwest(error) => error.is_connect() || error.is_timeout(),
+                    error => error.status().map_or(false, |status| {
+                        self.retryable_status_codes.contains(&status)
+                    }),
+                };
+                if !retryable {
+                    return None;
+                }
+                let retry_after = error.retry_after().filter(|_| self.respect_retry_after);
+                if let Some(retry_after) = retry_after {
+                    return (retry_after <= self.max_backoff).then_some(retry_after);
+                }
+                let backoff = self
+                    .backoff_base
+                    .saturating_mul(2u32.saturating_pow(attempt - 1))
+                    .min(self.max_backoff);
+                if self.jitter {
+                    Some(rand::thread_rng().gen_range(Duration::ZERO..=backoff))
+                } else {
+                    Some(backoff)
+                }
+            }
         }
 
-        const DEFAULT_TRAEWELLING_BASE_URL: &str = "https://traewelling.de/api/v1";
+        pub const DEFAULT_TRAEWELLING_BASE_URL: &str = "https://traewelling.de/api/v1";
+        /// Upper bound of pages fetched when following pagination, so a misbehaving
+        /// API can't keep us paginating forever. Streams with more pages end with
+        /// [`Error::TooManyPages`] rather than silently missing entries.
+        pub const MAX_PAGES: u32 = 100;
 
         impl Default for TraewellingClient {
             fn default() -> Self {
@@ -27,17 +160,18 @@ pub mod traewelling {
                     base_url: Url::parse(DEFAULT_TRAEWELLING_BASE_URL).unwrap(),
                     client: create_default_client(),
                     token: None,
+                    retry_policy: RetryPolicy::default(),
+                    rate_limit: Arc::default(),
+                    request_observer: None,
                 }
             }
         }
 
+        const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
         fn create_default_client() -> Client {
             Client::builder()
-                .user_agent(concat!(
-                    env!("CARGO_PKG_NAME"),
-                    "/",
-                    env!("CARGO_PKG_VERSION")
-                ))
+                .user_agent(USER_AGENT)
                 .build()
                 .expect("Failed to create reqwest client")
         }
@@ -47,6 +181,13 @@ pub mod traewelling {
             base_url: Option<Url>,
             client: Option<Client>,
             token: Option<String>,
+            retry_policy: Option<RetryPolicy>,
+            request_observer: Option<RequestObserver>,
+            timeout: Option<Duration>,
+            connect_timeout: Option<Duration>,
+            proxy: Option<Proxy>,
+            root_certificates: Vec<Certificate>,
+            user_agent_suffix: Option<String>,
         }
 
         impl TraewellingClientBuilder {
@@ -55,6 +196,9 @@ pub mod traewelling {
                 self
             }
 
+            /// Uses the given client for all requests. The HTTP options of this builder, like
+            /// timeouts and proxies, have to be set on the client itself then: combining them
+            /// with an injected client fails with [`Error::ConflictingClientOptions`].
             pub fn with_client(mut self, client: Client) -> Self {
                 self.client = Some(client);
                 self
@@ -65,14 +209,92 @@ pub mod traewelling {
                 self
             }
 
-            pub fn build(self) -> TraewellingClient {
-                TraewellingClient {
+            pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+                self.retry_policy = Some(retry_policy);
+                self
+            }
+
+            /// Calls the observer after every request, including each retry.
+            pub fn with_request_observer<F>(mut self, observer: F) -> Self
+            where
+                F: Fn(&RequestAttempt<'_>) + Send + Sync + 'static,
+            {
+                self.request_observer = Some(Arc::new(observer));
+                self
+            }
+
+            /// Timeout of a whole request, from connecting until the body is read.
+            pub fn with_timeout(mut self, timeout: Duration) -> Self {
+                self.timeout = Some(timeout);
+                self
+            }
+
+            pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+                self.connect_timeout = Some(connect_timeout);
+                self
+            }
+
+            pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+                self.proxy = Some(proxy);
+                self
+            }
+
+            /// Trusts the given certificate in addition to the built-in root certificates.
+            pub fn with_root_certificate(mut self, certificate: Certificate) -> Self {
+                self.root_certificates.push(certificate);
+                self
+            }
+
+            /// Appended to the default User-Agent, e.g. to identify the deployment.
+            pub fn with_user_agent_suffix<T: Into<String>>(mut self, suffix: T) -> Self {
+                self.user_agent_suffix = Some(suffix.into());
+                self
+            }
+
+            pub fn build(self) -> Result<TraewellingClient, Error> {
+                let client = match self.client {
+                    Some(client) => {
+                        if self.timeout.is_some()
+                            || self.connect_timeout.is_some()
+                            || self.proxy.is_some()
+                            || !self.root_certificates.is_empty()
+                            || self.user_agent_suffix.is_some()
+                        {
+                            return Err(Error::ConflictingClientOptions);
+                        }
+                        client
+                    }
+                    None => {
+                        let user_agent = match self.user_agent_suffix {
+                            Some(suffix) => format!("{USER_AGENT} {suffix}"),
+                            None => USER_AGENT.to_string(),
+                        };
+                        let mut builder = Client::builder().user_agent(user_agent);
+                        if let Some(timeout) = self.timeout {
+                            builder = builder.timeout(timeout);
+                        }
+                        if let Some(connect_timeout) = self.connect_timeout {
+                            builder = builder.connect_timeout(connect_timeout);
+                        }
+                        if let Some(proxy) = self.proxy {
+                            builder = builder.proxy(proxy);
+                        }
+                        for certificate in self.root_certificates {
+                            builder = builder.add_root_certificate(certificate);
+                        }
+                        builder.build()?
+                    }
+                };
+                Ok(TraewellingClient {
                     base_url: self
                         .base_url
                         .unwrap_or_else(|| Url::parse(DEFAULT_TRAEWELLING_BASE_URL).unwrap()),
-                    client: create_default_client(),
+                    client,
                     token: self.token,
-                }
+                    retry_policy: self.retry_policy.unwrap_or_default(),
+                    rate_limit: Arc::default(),
+                    request_observer: self.request_observer,
+                })
             }
         }
 
@@ -83,6 +305,164 @@ pub mod traewelling {
             pub fn statuses(&self) -> StatusCategory {
                 StatusCategory { client: self }
             }
+
+            pub fn users(&self) -> UserCategory {
+                UserCategory { client: self }
+            }
+
+            pub fn statistics(&self) -> StatisticsCategory {
+                StatisticsCategory { client: self }
+            }
+
+            pub fn leaderboards(&self) -> LeaderboardCategory {
+                LeaderboardCategory { client: self }
+            }
+
+            pub fn events(&self) -> EventCategory {
+                EventCategory { client: self }
+            }
+
+            pub fn trains(&self) -> TrainsCategory {
+                TrainsCategory { client: self }
+            }
+
+            /// The rate limit state of the most recent response, if Traewelling reported one.
+            pub fn rate_limit(&self) -> Option<RateLimit> {
+                *self.rate_limit.lock().unwrap()
+            }
+
+            /// Builds a GET request to the given path segments, see [`Self::url`].
+            fn get_segments(&self, segments: &[&str]) -> RequestBuilder {
+                self.get_url(self.url(segments))
+            }
+
+            /// Appends the given path segments to the base URL, percent-encoding them. A
+            /// trailing slash of the base URL is ignored.
+            fn url(&self, segments: &[&str]) -> Url {
+                let mut url = self.base_url.clone();
+                url.path_segments_mut()
+                    .expect("Base URL cannot be a base")
+                    .pop_if_empty()
+                    .extend(segments);
+                url
+            }
+
+            fn get_url<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+                let request = self.client.get(url);
+                match self.token.as_ref() {
+                    Some(token) => request.bearer_auth(token.as_str()),
+                    None => request,
+                }
+            }
+
+            async fn get_page<T: DeserializeOwned>(
+                &self,
+                url: Url,
+                page: u32,
+            ) -> Result<PaginatedResponse<T>, Error> {
+                self.send(self.get_url(url).query(&[("page", page)])).await
+            }
+
+            /// Streams the pages of the given URL, following the pagination until there is
+            /// no next page. Fails with [`Error::TooManyPages`] if there are more than
+            /// [`MAX_PAGES`].
+            fn paginate<'a, T: DeserializeOwned + 'a>(
+                &'a self,
+                url: Url,
+            ) -> impl Stream<Item = Result<PaginatedResponse<T>, Error>> + 'a {
+                stream::try_unfold(Some(1), move |page| {
+                    let url = url.clone();
+                    async move {
+                        let Some(page) = page else {
+                            return Ok(None);
+                        };
+                        if page > MAX_PAGES {
+                            tracing::warn!("{} has more than {} pages, giving up", url, MAX_PAGES);
+                            return Err(Error::TooManyPages(MAX_PAGES));
+                        }
+                        let response: PaginatedResponse<T> = self.get_page(url, page).await?;
+                        let next_page = response.has_next_page().then_some(page + 1);
+                        Ok(Some((response, next_page)))
+                    }
+                })
+            }
+
+            /// Sends the request, retrying it according to the [`RetryPolicy`].
+            async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, Error> {
+                let mut attempt = 1;
+                loop {
+                    // Requests with a streaming body can't be cloned and thus not be retried
+                    let Some(current) = request.try_clone() else {
+                        return self.send_observed(request).await;
+                    };
+                    let error = match self.send_observed(current).await {
+                        Ok(response) => return Ok(response),
+                        Err(error) => error,
+                    };
+                    let Some(delay) = self.retry_policy.retry_delay(attempt, &error) else {
+                        return Err(error);
+                    };
+                    tracing::debug!(
+                        "Request failed on attempt {}, retrying in {:?}: {}",
+                        attempt,
+                        delay,
+                        error
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+
+            async fn send_observed<T: DeserializeOwned>(
+                &self,
+                request: RequestBuilder,
+            ) -> Result<T, Error> {
+                let started = Instant::now();
+                let result = self.send_once(request).await;
+                if let Some(observer) = &self.request_observer {
+                    observer(&RequestAttempt {
+                        duration: started.elapsed(),
+                        error: result.as_ref().err(),
+                    });
+                }
+                result
+            }
+
+            async fn send_once<T: DeserializeOwned>(
+                &self,
+                request: RequestBuilder,
+            ) -> Result<T, Error> {
+                let response = request.send().await?;
+                if let Some(rate_limit) = RateLimit::from_headers(response.headers()) {
+                    *self.rate_limit.lock().unwrap() = Some(rate_limit);
+                }
+                if !response.status().is_success() {
+                    return Err(Error::from_response(crate::TrwlErrorResponse::new(
+                        response.status(),
+                        parse_retry_after(response.headers()),
+                        response.text().await?,
+                    )));
+                }
+                let body = response.bytes().await?;
+                let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+                serde_path_to_error::deserialize(deserializer).map_err(|error| Error::Deserialize {
+                    path: error.path().to_string(),
+                    source: error.into_inner(),
+                })
+            }
+        }
+
+        /// Parses the `Retry-After` header, which is either in seconds or an HTTP date.
+        fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+            let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+            if let Ok(seconds) = value.parse() {
+                return Some(Duration::from_secs(seconds));
+            }
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            date.with_timezone(&Utc)
+                .signed_duration_since(Utc::now())
+                .to_std()
+                .ok()
         }
 
         pub struct StatusCategory<'a> {
@@ -90,28 +470,318 @@ pub mod traewelling {
         }
 
         impl<'a> StatusCategory<'a> {
+            /// Fetches the first page of active statuses.
             pub async fn get_active_statuses(&self) -> Result<ActiveStatusesResponse, Error> {
-                let mut request = self
+                self.get_active_statuses_page(1).await
+            }
+
+            pub async fn get_active_statuses_page(
+                &self,
+                page: u32,
+            ) -> Result<ActiveStatusesResponse, Error> {
+                self.client
+                    .get_page(self.client.url(&["statuses"]), page)
+                    .await
+            }
+
+            /// Fetches every page of active statuses, up to [`MAX_PAGES`].
+            ///
+            /// Check-ins created while paging shift the following pages, so a status may
+            /// be returned twice. Only its first occurrence is kept.
+            pub async fn get_all_active_statuses(&self) -> Result<Vec<Status>, Error> {
+                let mut seen = HashSet::new();
+                self.stream_active_statuses()
+                    .try_filter(|status| future::ready(seen.insert(status.id)))
+                    .try_collect()
+                    .await
+            }
+
+            /// Streams the pages of active statuses, following the pagination until
+            /// there is no next page, failing after [`MAX_PAGES`].
+            pub fn stream_active_statuses_pages(
+                &self,
+            ) -> impl Stream<Item = Result<ActiveStatusesResponse, Error>> + 'a {
+                self.client.paginate(self.client.url(&["statuses"]))
+            }
+
+            /// Streams the active statuses of all pages, see [`Self::stream_active_statuses_pages`].
+            pub fn stream_active_statuses(&self) -> impl Stream<Item = Result<Status, Error>> + 'a {
+                flatten_pages(self.stream_active_statuses_pages())
+            }
+        }
+
+        pub struct UserCategory<'a> {
+            client: &'a TraewellingClient,
+        }
+
+        impl<'a> UserCategory<'a> {
+            pub async fn get_user(&self, username: &str) -> Result<User, Error> {
+                let request = self.client.get_segments(&["user", username]);
+                let response: DataResponse<User> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+
+            /// Fetches the user the token belongs to.
+            pub async fn get_authenticated_user(&self) -> Result<AuthenticatedUser, Error> {
+                let request = self.client.get_segments(&["auth", "user"]);
+                let response: DataResponse<AuthenticatedUser> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+
+            pub async fn get_statuses_page(
+                &self,
+                username: &str,
+                page: u32,
+            ) -> Result<PaginatedResponse<Status>, Error> {
+                let url = self.client.url(&["user", username, "statuses"]);
+                self.client.get_page(url, page).await
+            }
+
+            /// Streams the statuses of the user of all pages, up to [`MAX_PAGES`].
+            pub fn stream_statuses(
+                &self,
+                username: &str,
+            ) -> impl Stream<Item = Result<Status, Error>> + 'a {
+                let url = self.client.url(&["user", username, "statuses"]);
+                flatten_pages(self.client.paginate(url))
+            }
+
+            /// Counts the followers of the authenticated user.
+            pub async fn count_followers(&self) -> Result<usize, Error> {
+                self.count(&["user", "self", "followers"]).await
+            }
+
+            /// Counts the users the authenticated user follows.
+            pub async fn count_followings(&self) -> Result<usize, Error> {
+                self.count(&["user", "self", "followings"]).await
+            }
+
+            /// Counts the entries of all pages at the given path segments.
+            async fn count(&self, segments: &[&str]) -> Result<usize, Error> {
+                self.client
+                    .paginate::<IgnoredAny>(self.client.url(segments))
+                    .try_fold(0, |count, page| async move {
+                        Ok::<_, Error>(count + page.data.len())
+                    })
+                    .await
+            }
+        }
+
+        pub struct StatisticsCategory<'a> {
+            client: &'a TraewellingClient,
+        }
+
+        impl<'a> StatisticsCategory<'a> {
+            /// Fetches the travel statistics of the authenticated user in the given period.
+            pub async fn get_statistics(
+                &self,
+                from: DateTime<Utc>,
+                until: DateTime<Utc>,
+            ) -> Result<Statistics, Error> {
+                let request = self
                     .client
+                    .get_segments(&["statistics"])
+                    .query(&[("from", from.to_rfc3339()), ("until", until.to_rfc3339())]);
+                let response: DataResponse<Statistics> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+        }
+
+        pub struct LeaderboardCategory<'a> {
+            client: &'a TraewellingClient,
+        }
+
+        impl<'a> LeaderboardCategory<'a> {
+            /// The users with the most points in the last days.
+            pub async fn get_leaderboard(&self) -> Result<Vec<LeaderboardEntry>, Error> {
+                self.get(&["leaderboard"]).await
+            }
+
+            /// The users with the longest distance travelled in the last days.
+            pub async fn get_distance_leaderboard(&self) -> Result<Vec<LeaderboardEntry>, Error> {
+                self.get(&["leaderboard", "distance"]).await
+            }
+
+            /// The leaderboard of the authenticated user and the users they follow.
+            pub async fn get_friends_leaderboard(&self) -> Result<Vec<LeaderboardEntry>, Error> {
+                self.get(&["leaderboard", "friends"]).await
+            }
+
+            /// The users with the most points in the given month.
+            pub async fn get_monthly_leaderboard(
+                &self,
+                year: i32,
+                month: u32,
+            ) -> Result<Vec<LeaderboardEntry>, Error> {
+                self.get(&["leaderboard", &format!("{year:04}-{month:02}")])
+                    .await
+            }
+
+            async fn get(&self, segments: &[&str]) -> Result<Vec<LeaderboardEntry>, Error> {
+                let response: DataResponse<Vec<LeaderboardEntry>> =
+                    self.client.send(self.client.get_segments(segments)).await?;
+                Ok(response.data)
+            }
+        }
+
+        pub struct EventCategory<'a> {
+            client: &'a TraewellingClient,
+        }
+
+        impl<'a> EventCategory<'a> {
+            /// Streams the current and upcoming events of all pages, up to [`MAX_PAGES`].
+            pub fn stream_events(&self) -> impl Stream<Item = Result<EventDetails, Error>> + 'a {
+                flatten_pages(self.client.paginate(self.client.url(&["events"])))
+            }
+
+            pub async fn get_event(&self, slug: &str) -> Result<EventDetails, Error> {
+                let request = self.client.get_segments(&["event", slug]);
+                let response: DataResponse<EventDetails> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+
+            /// Fetches the total distance and duration travelled to the event.
+            pub async fn get_event_statistics(&self, slug: &str) -> Result<EventStatistics, Error> {
+                let request = self.client.get_segments(&["event", slug, "details"]);
+                let response: DataResponse<EventStatistics> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+
+            /// Streams the statuses checked in to the event of all pages, up to [`MAX_PAGES`].
+            pub fn stream_event_statuses(
+                &self,
+                slug: &str,
+            ) -> impl Stream<Item = Result<Status, Error>> + 'a {
+                let url = self.client.url(&["event", slug, "statuses"]);
+                flatten_pages(self.client.paginate(url))
+            }
+        }
+
+        pub struct TrainsCategory<'a> {
+            client: &'a TraewellingClient,
+        }
+
+        impl<'a> TrainsCategory<'a> {
+            /// Searches stations by (a part of) their name or their DS100 abbreviation.
+            pub async fn autocomplete_station(
+                &self,
+                query: &str,
+            ) -> Result<Vec<StationSuggestion>, Error> {
+                let request =
+                    self.client
+                        .get_segments(&["trains", "station", "autocomplete", query]);
+                let response: DataResponse<Vec<StationSuggestion>> =
+                    self.client.send(request).await?;
+                Ok(response.data)
+            }
+
+            /// Finds the station closest to the given coordinates.
+            pub async fn get_nearby_station(
+                &self,
+                latitude: f64,
+                longitude: f64,
+            ) -> Result<Station, Error> {
+                let request = self
                     .client
-                    .get(format!("{}/statuses", self.client.base_url));
-                if let Some(token) = self.client.token.as_ref() {
-                    request = request.bearer_auth(token.as_str());
+                    .get_segments(&["trains", "station", "nearby"])
+                    .query(&[("latitude", latitude), ("longitude", longitude)]);
+                let response: DataResponse<Station> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+
+            /// Fetches the departure board of the station, optionally starting at `when`
+            /// and only containing the given travel type, e.g. `express` or `regional`.
+            pub async fn get_departures(
+                &self,
+                station: &str,
+                when: Option<DateTime<Utc>>,
+                travel_type: Option<&str>,
+            ) -> Result<DeparturesResponse, Error> {
+                let mut request =
+                    self.client
+                        .get_segments(&["trains", "station", station, "departures"]);
+                if let Some(when) = when {
+                    request = request.query(&[("when", when.to_rfc3339())]);
                 }
-                let response = request.send().await?;
-                if !response.status().is_success() {
-                    return Err(Error::InvalidTrwlResponse(crate::TrwlErrorResponse {
-                        status_code: response.status(),
-                        message: response.text().await?,
-                    }));
+                if let Some(travel_type) = travel_type {
+                    request = request.query(&[("travelType", travel_type)]);
                 }
-                Ok(response.json().await?)
+                self.client.send(request).await
+            }
+
+            /// Fetches a trip with all of its stopovers.
+            ///
+            /// `start` is the EVA identifier of the station the trip is boarded at.
+            pub async fn get_trip(
+                &self,
+                hafas_trip_id: &str,
+                line_name: &str,
+                start: EvaIdentifier,
+            ) -> Result<Trip, Error> {
+                let request = self.client.get_segments(&["trains", "trip"]).query(&[
+                    ("hafasTripId", hafas_trip_id),
+                    ("lineName", line_name),
+                    ("start", start.to_string().as_str()),
+                ]);
+                let response: DataResponse<Trip> = self.client.send(request).await?;
+                Ok(response.data)
+            }
+        }
+
+        /// Flattens a stream of pages into a stream of their entries.
+        fn flatten_pages<T>(
+            pages: impl Stream<Item = Result<PaginatedResponse<T>, Error>>,
+        ) -> impl Stream<Item = Result<T, Error>> {
+            pages
+                .map_ok(|page| stream::iter(page.data.into_iter().map(Ok::<T, Error>)))
+                .try_flatten()
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        struct DataResponse<T> {
+            data: T,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        pub struct PaginatedResponse<T> {
+            pub data: Vec<T>,
+            pub links: Option<PaginationLinks>,
+            pub meta: Option<PaginationMeta>,
+        }
+
+        impl<T> PaginatedResponse<T> {
+            pub fn has_next_page(&self) -> bool {
+                self.links
+                    .as_ref()
+                    .map_or(false, |links| links.next.is_some())
             }
         }
 
+        pub type ActiveStatusesResponse = PaginatedResponse<Status>;
+
+        /// Identifier of a station in the EVA numbering of Deutsche Bahn, called IBNR
+        /// by some endpoints.
+        pub type EvaIdentifier = i64;
+
+        /// Traewelling reports all durations in minutes, while we prefer seconds.
+        fn minutes_to_seconds(minutes: i64) -> i64 {
+            minutes * 60
+        }
+
         #[derive(Debug, Deserialize, Serialize)]
-        pub struct ActiveStatusesResponse {
-            pub data: Vec<Status>,
+        pub struct PaginationLinks {
+            pub first: Option<String>,
+            pub last: Option<String>,
+            pub prev: Option<String>,
+            pub next: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        pub struct PaginationMeta {
+            pub current_page: u32,
+            pub from: Option<u32>,
+            pub per_page: u32,
+            pub to: Option<u32>,
         }
         #[derive(Debug, Deserialize, Serialize)]
         #[serde(rename_all = "camelCase")]
@@ -135,12 +805,97 @@ pub mod traewelling {
             pub line_name: String,
             pub distance: i32,
             pub points: i32,
+            /// Duration of the journey, in minutes.
             pub duration: i32,
             pub speed: f64,
             pub origin: TrainStopover,
             pub destination: TrainStopover,
         }
 
+        impl Train {
+            pub fn duration_seconds(&self) -> i64 {
+                minutes_to_seconds(self.duration.into())
+            }
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct User {
+            pub id: i32,
+            pub display_name: String,
+            pub username: String,
+            pub profile_picture: Option<String>,
+            /// Total distance travelled, in meters.
+            pub train_distance: i64,
+            /// Total duration travelled, in minutes.
+            pub train_duration: i64,
+            /// Average speed, in km/h.
+            pub train_speed: Option<f64>,
+            pub points: i64,
+            pub twitter_url: Option<String>,
+            pub mastodon_url: Option<String>,
+            pub private_profile: bool,
+        }
+
+        impl User {
+            pub fn train_duration_seconds(&self) -> i64 {
+                minutes_to_seconds(self.train_duration)
+            }
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct AuthenticatedUser {
+            #[serde(flatten)]
+            pub user: User,
+            pub email: Option<String>,
+            pub language: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct Statistics {
+            #[serde(default)]
+            pub categories: Vec<StatisticsEntry>,
+            #[serde(default)]
+            pub operators: Vec<StatisticsEntry>,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct StatisticsEntry {
+            /// The category or operator, `None` if Traewelling doesn't know the operator.
+            pub name: Option<String>,
+            /// Number of check-ins.
+            pub count: i64,
+            /// Total duration, in minutes.
+            pub duration: i64,
+        }
+
+        impl StatisticsEntry {
+            pub fn duration_seconds(&self) -> i64 {
+                minutes_to_seconds(self.duration)
+            }
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct LeaderboardEntry {
+            pub username: String,
+            pub display_name: String,
+            /// Distance travelled, in meters.
+            pub train_distance: i64,
+            /// Duration travelled, in minutes.
+            pub train_duration: i64,
+            pub points: i64,
+        }
+
+        impl LeaderboardEntry {
+            pub fn train_duration_seconds(&self) -> i64 {
+                minutes_to_seconds(self.train_duration)
+            }
+        }
+
         #[derive(Debug, Deserialize, Serialize)]
         #[serde(rename_all = "camelCase")]
         pub struct Event {
@@ -148,12 +903,108 @@ pub mod traewelling {
             pub name: String,
         }
 
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct EventDetails {
+            pub id: i32,
+            pub name: String,
+            pub slug: String,
+            pub hashtag: Option<String>,
+            pub host: Option<String>,
+            pub url: Option<String>,
+            pub begin: DateTime<FixedOffset>,
+            pub end: DateTime<FixedOffset>,
+            pub station: Option<Station>,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct EventStatistics {
+            pub id: i32,
+            pub slug: String,
+            /// Total distance travelled to the event, in meters.
+            pub train_distance: i64,
+            /// Total duration travelled to the event, in minutes.
+            pub train_duration: i64,
+        }
+
+        impl EventStatistics {
+            pub fn train_duration_seconds(&self) -> i64 {
+                minutes_to_seconds(self.train_duration)
+            }
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct Station {
+            pub id: i32,
+            pub name: String,
+            pub latitude: f64,
+            pub longitude: f64,
+            pub ibnr: Option<EvaIdentifier>,
+            pub ril_identifier: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct StationSuggestion {
+            pub ibnr: EvaIdentifier,
+            pub ril_identifier: Option<String>,
+            pub name: String,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        pub struct DeparturesResponse {
+            pub data: Vec<Departure>,
+            pub meta: DeparturesMeta,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        pub struct DeparturesMeta {
+            pub station: Station,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct Departure {
+            pub trip_id: String,
+            pub direction: Option<String>,
+            pub when: Option<DateTime<FixedOffset>>,
+            pub planned_when: Option<DateTime<FixedOffset>>,
+            /// Delay in seconds.
+            pub delay: Option<i32>,
+            pub platform: Option<String>,
+            pub planned_platform: Option<String>,
+            pub line: Line,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct Line {
+            pub name: Option<String>,
+            pub fahrt_nr: Option<String>,
+            pub product: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        pub struct Trip {
+            pub id: i32,
+            pub category: String,
+            pub number: String,
+            pub line_name: String,
+            pub journey_number: Option<i32>,
+            pub origin: Station,
+            pub destination: Station,
+            pub stopovers: Vec<TrainStopover>,
+        }
+
         #[derive(Debug, Deserialize, Serialize)]
         #[serde(rename_all = "camelCase")]
         pub struct TrainStopover {
             pub id: i32,
             pub name: String,
-            pub eva_identifier: i32,
+            pub eva_identifier: EvaIdentifier,
             pub arrival: Option<DateTime<FixedOffset>>,
             pub arrival_planned: Option<DateTime<FixedOffset>>,
             pub arrival_real: Option<DateTime<FixedOffset>>,
@@ -168,6 +1019,235 @@ pub mod traewelling {
             pub is_departure_delayed: bool,
             pub cancelled: bool,
         }
+
+        #[cfg(test)]
+        mod tests {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+
+            use tokio::{
+                io::{AsyncReadExt, AsyncWriteExt},
+                net::{TcpListener, TcpStream},
+            };
+
+            use super::*;
+
+            fn response(status: &str, headers: &str, body: &str) -> String {
+                format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n{headers}\r\n{body}",
+                    body.len()
+                )
+            }
+
+            fn ok() -> String {
+                response("200 OK", "", r#"{"data":"ok"}"#)
+            }
+
+            fn unavailable(headers: &str) -> String {
+                response("503 Service Unavailable", headers, "")
+            }
+
+            /// Serves the given responses one per connection and counts the requests.
+            async fn serve(responses: Vec<String>) -> (Url, Arc<AtomicUsize>) {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let url =
+                    Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+                let requests = Arc::new(AtomicUsize::new(0));
+                let counter = requests.clone();
+                tokio::spawn(async move {
+                    for response in responses {
+                        let (mut stream, _) = listener.accept().await.unwrap();
+                        read_request(&mut stream).await;
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+                (url, requests)
+            }
+
+            async fn read_request(stream: &mut TcpStream) {
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        return;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+            }
+
+            fn client(url: Url, retry_policy: RetryPolicy) -> TraewellingClient {
+                TraewellingClient::builder()
+                    .with_base_url(url)
+                    .with_retry_policy(retry_policy)
+                    .build()
+                    .unwrap()
+            }
+
+            fn retry_policy() -> RetryPolicy {
+                RetryPolicy {
+                    backoff_base: Duration::from_millis(1),
+                    jitter: false,
+                    ..RetryPolicy::default()
+                }
+            }
+
+            async fn get(client: &TraewellingClient) -> Result<DataResponse<String>, Error> {
+                client.send(client.get_segments(&["test"])).await
+            }
+
+            #[test]
+            fn encodes_path_segments() {
+                let client = TraewellingClient::builder().build().unwrap();
+                assert_eq!(
+                    client.url(&["user", "a/b?c", "statuses"]).as_str(),
+                    "https://traewelling.de/api/v1/user/a%2Fb%3Fc/statuses"
+                );
+            }
+
+            #[test]
+            fn ignores_trailing_slash_of_base_url() {
+                for base_url in [
+                    "https://traewelling.de/api/v1",
+                    "https://traewelling.de/api/v1/",
+                ] {
+                    let client = TraewellingClient::builder()
+                        .with_base_url(Url::parse(base_url).unwrap())
+                        .build()
+                        .unwrap();
+                    assert_eq!(
+                        client.url(&["auth", "user"]).as_str(),
+                        "https://traewelling.de/api/v1/auth/user"
+                    );
+                }
+            }
+
+            #[test]
+            fn rejects_http_options_with_injected_client() {
+                let result = TraewellingClient::builder()
+                    .with_client(Client::new())
+                    .with_timeout(Duration::from_secs(1))
+                    .build();
+                assert!(matches!(result, Err(Error::ConflictingClientOptions)));
+            }
+
+            fn statuses_page(ids: &[i32], next: Option<&str>) -> String {
+                let stopover = serde_json::json!({
+                    "id": 1,
+                    "name": "Hamburg Hbf",
+                    "evaIdentifier": 8002549,
+                    "isArrivalDelayed": false,
+                    "isDepartureDelayed": false,
+                    "cancelled": false,
+                });
+                let statuses = ids.iter().map(|id| {
+                    serde_json::json!({
+                        "id": id,
+                        "user": 1,
+                        "username": "alice",
+                        "business": 0,
+                        "createdAt": "2023-01-15T12:00:00+01:00",
+                        "train": {
+                            "trip": 1,
+                            "hafasId": "1|2|3",
+                            "category": "suburban",
+                            "number": "S1",
+                            "lineName": "S1",
+                            "distance": 1000,
+                            "points": 1,
+                            "duration": 10,
+                            "speed": 60.0,
+                            "origin": stopover,
+                            "destination": stopover,
+                        },
+                    })
+                });
+                let body = serde_json::json!({
+                    "data": statuses.collect::<Vec<_>>(),
+                    "links": { "next": next },
+                });
+                response("200 OK", "", &body.to_string())
+            }
+
+            #[tokio::test]
+            async fn skips_statuses_shifted_to_the_next_page() {
+                let (url, _) = serve(vec![
+                    statuses_page(&[3, 2], Some("page=2")),
+                    statuses_page(&[2, 1], None),
+                ])
+                .await;
+                let statuses = client(url, retry_policy())
+                    .statuses()
+                    .get_all_active_statuses()
+                    .await
+                    .unwrap();
+                let ids: Vec<_> = statuses.iter().map(|status| status.id).collect();
+                assert_eq!(ids, [3, 2, 1]);
+            }
+
+            #[tokio::test]
+            async fn retries_unavailable_until_success() {
+                let (url, requests) = serve(vec![unavailable(""), ok()]).await;
+                let response = get(&client(url, retry_policy())).await.unwrap();
+                assert_eq!(response.data, "ok");
+                assert_eq!(requests.load(Ordering::SeqCst), 2);
+            }
+
+            #[tokio::test]
+            async fn observes_every_attempt() {
+                let (url, _) = serve(vec![unavailable(""), ok()]).await;
+                let attempts = Arc::new(Mutex::new(Vec::new()));
+                let observed = attempts.clone();
+                let client = TraewellingClient::builder()
+                    .with_base_url(url)
+                    .with_retry_policy(retry_policy())
+                    .with_request_observer(move |attempt| {
+                        let status = attempt.error.and_then(Error::status);
+                        observed.lock().unwrap().push(status);
+                    })
+                    .build()
+                    .unwrap();
+                get(&client).await.unwrap();
+                assert_eq!(
+                    *attempts.lock().unwrap(),
+                    [Some(StatusCode::SERVICE_UNAVAILABLE), None]
+                );
+            }
+
+            #[tokio::test]
+            async fn does_not_retry_not_found() {
+                let (url, requests) = serve(vec![response("404 Not Found", "", ""), ok()]).await;
+                let error = get(&client(url, retry_policy())).await.unwrap_err();
+                assert!(matches!(error, Error::NotFound(_)));
+                assert_eq!(requests.load(Ordering::SeqCst), 1);
+            }
+
+            #[tokio::test]
+            async fn gives_up_after_max_attempts() {
+                let (url, requests) = serve(vec![unavailable(""); 5]).await;
+                let error = get(&client(url, retry_policy())).await.unwrap_err();
+                assert_eq!(error.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+                assert_eq!(requests.load(Ordering::SeqCst), 3);
+            }
+
+            #[tokio::test]
+            async fn waits_for_retry_after() {
+                let (url, requests) = serve(vec![unavailable("Retry-After: 1\r\n"), ok()]).await;
+                let started = Instant::now();
+                get(&client(url, retry_policy())).await.unwrap();
+                assert!(started.elapsed() >= Duration::from_secs(1));
+                assert_eq!(requests.load(Ordering::SeqCst), 2);
+            }
+
+            #[tokio::test]
+            async fn does_not_retry_when_retry_after_exceeds_max_backoff() {
+                let (url, requests) = serve(vec![unavailable("Retry-After: 60\r\n"), ok()]).await;
+                let error = get(&client(url, retry_policy())).await.unwrap_err();
+                assert_eq!(error.retry_after(), Some(Duration::from_secs(60)));
+                assert_eq!(requests.load(Ordering::SeqCst), 1);
+            }
+        }
     }
 }
 
@@ -175,13 +1255,121 @@ pub mod traewelling {
 pub enum Error {
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
-    #[error("Got an invalid response from traewelling: {0:#?}")]
+    #[error("Traewelling rejected the token: {0}")]
+    Unauthorized(TrwlErrorResponse),
+    #[error("Not allowed to access this resource: {0}")]
+    Forbidden(TrwlErrorResponse),
+    #[error("Resource not found: {0}")]
+    NotFound(TrwlErrorResponse),
+    #[error("Rate limited by traewelling: {0}")]
+    RateLimited(TrwlErrorResponse),
+    #[error("Traewelling failed to handle the request: {0}")]
+    ServerError(TrwlErrorResponse),
+    #[error("Got an invalid response from traewelling: {0}")]
     InvalidTrwlResponse(TrwlErrorResponse),
+    #[error("Traewelling returned more than {0} pages")]
+    TooManyPages(u32),
+    #[error("HTTP options can't be combined with an injected client")]
+    ConflictingClientOptions,
+    #[error("Failed to decode the response of traewelling at `{path}`: {source}")]
+    Deserialize {
+        /// Path of the JSON value which didn't match, e.g. `data[3].train.origin`.
+        path: String,
+        source: serde_json::Error,
+    },
 }
 
+impl Error {
+    fn from_response(response: TrwlErrorResponse) -> Error {
+        match response.status_code {
+            StatusCode::UNAUTHORIZED => Error::Unauthorized(response),
+            StatusCode::FORBIDDEN => Error::Forbidden(response),
+            StatusCode::NOT_FOUND => Error::NotFound(response),
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited(response),
+            status if status.is_server_error() => Error::ServerError(response),
+            _ => Error::InvalidTrwlResponse(response),
+        }
+    }
+
+    /// The error response of Traewelling, if the request failed because of its status code.
+    pub fn response(&self) -> Option<&TrwlErrorResponse> {
+        match self {
+            Error::Unauthorized(response)
+            | Error::Forbidden(response)
+            | Error::NotFound(response)
+            | Error::RateLimited(response)
+            | Error::ServerError(response)
+            | Error::InvalidTrwlResponse(response) => Some(response),
+            Error::Reqwest(_)
+            | Error::TooManyPages(_)
+            | Error::ConflictingClientOptions
+            | Error::Deserialize { .. } => None,
+        }
+    }
+
+    /// The HTTP status code of the failed request, if Traewelling responded.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Reqwest(error) => error.status(),
+            error => error.response().map(|response| response.status_code),
+        }
+    }
+
+    /// How long Traewelling asked to wait before sending another request.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.response()?.retry_after
+    }
+
+    /// Whether the response body could not be decoded.
+    pub fn is_decode(&self) -> bool {
+        match self {
+            Error::Reqwest(error) => error.is_decode(),
+            Error::Deserialize { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// A response of Traewelling with an unsuccessful status code.
 #[derive(Debug)]
-#[allow(dead_code)] // Debug message
 pub struct TrwlErrorResponse {
-    status_code: StatusCode,
-    message: String,
+    pub status_code: StatusCode,
+    /// The message of Traewelling's JSON error body, if there is one.
+    pub message: Option<String>,
+    /// The raw response body.
+    pub body: String,
+    /// Value of the `Retry-After` header.
+    pub retry_after: Option<Duration>,
+}
+
+impl TrwlErrorResponse {
+    fn new(status_code: StatusCode, retry_after: Option<Duration>, body: String) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            message: String,
+        }
+
+        let message = serde_json::from_str::<ErrorBody>(&body)
+            .ok()
+            .map(|error| error.message);
+        Self {
+            status_code,
+            message,
+            body,
+            retry_after,
+        }
+    }
+}
+
+impl Display for TrwlErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status_code)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        if let Some(retry_after) = self.retry_after {
+            write!(f, " (retry after {}s)", retry_after.as_secs())?;
+        }
+        Ok(())
+    }
 }
@@ -1,56 +1,235 @@
 #![feature(const_slice_index)]
 
+mod config;
+mod events;
+mod history;
+mod leaderboard;
+mod personal;
+
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use axum::{extract::State, response::Redirect, routing::get, Router};
-use cached::proc_macro::cached;
-use itertools::Itertools;
+use axum::{
+    extract::{Query, State},
+    response::Redirect,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use clap::Parser;
+use config::{Args, Config, InstanceConfig};
+use events::EventMetrics;
+use history::{History, Store};
+use leaderboard::{LeaderboardMetrics, LeaderboardOptions};
+use personal::PersonalMetrics;
 use prometheus::{
-    opts, register_int_counter, register_int_gauge_vec, IntCounter, IntGaugeVec, Registry,
-    TextEncoder,
+    opts, proto::MetricFamily, register_gauge_vec_with_registry, register_gauge_with_registry,
+    register_histogram_with_registry, register_int_counter_vec_with_registry,
+    register_int_counter_with_registry, register_int_gauge_vec_with_registry,
+    register_int_gauge_with_registry, Gauge, GaugeVec, Histogram, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Registry, TextEncoder,
 };
 use reqwest::StatusCode;
-use traewelling_exporter::traewelling::client::TraewellingClient;
-
-lazy_static::lazy_static! {
-    static ref CLIENT: TraewellingClient = TraewellingClient::builder()
-        .with_base_url(
-            std::env::var("TRAEWELLING_API")
-                .ok()
-                .and_then(|var| var.parse().ok())
-                .unwrap_or_else(|| "https://traewelling.de/api/v1".parse().unwrap()),
-        )
-        .with_token(std::env::var("TRAEWELLING_TOKEN").ok())
-        .build();
-}
+use serde::Deserialize;
+use tokio::{sync::RwLock, time::MissedTickBehavior};
+use tracing::Instrument;
+use traewelling_exporter::traewelling::client::{Certificate, Proxy, Status, TraewellingClient};
+use url::Url;
+
+/// Upper bound of the factor the poll interval is stretched by when running low on
+/// rate limit.
+const MAX_POLL_SLOWDOWN: u32 = 8;
+/// Number of status IDs remembered to count every check-in only once.
+const MAX_SEEN_STATUSES: usize = 10_000;
+/// Minutes before its arrival a vanished journey is still considered completed, to
+/// allow for clock skew between Traewelling and the exporter.
+const ARRIVAL_TOLERANCE_MINUTES: i64 = 5;
+const DELAY_BUCKETS: [f64; 10] = [
+    -60.0, 0.0, 60.0, 120.0, 300.0, 600.0, 900.0, 1800.0, 3600.0, 7200.0,
+];
+const DISTANCE_BUCKETS: [f64; 9] = [
+    1000.0, 5000.0, 10000.0, 25000.0, 50000.0, 100000.0, 250000.0, 500000.0, 1000000.0,
+];
+const DURATION_BUCKETS: [f64; 8] = [
+    300.0, 600.0, 1200.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0,
+];
+const SPEED_BUCKETS: [f64; 9] = [10.0, 20.0, 40.0, 60.0, 80.0, 100.0, 150.0, 200.0, 300.0];
+const POINTS_BUCKETS: [f64; 7] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0];
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
     let _ = dotenvy::dotenv();
+    let args = Args::parse();
+    let config = Config::load(args.config.as_deref(), args.options)?;
+    if args.check_config {
+        println!("{config}");
+        return Ok(());
+    }
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .init();
 
-    let metrics = create_metrics()?;
-
-    let app_state = AppState { metrics };
+    let store = config.database.as_deref().map(Store::open).transpose()?;
+    let instances = config
+        .instances
+        .iter()
+        .map(|instance| start_instance(&config, instance, store.as_ref()))
+        .collect::<Result<_, _>>()?;
+    let listen_address = config.listen_address;
+    let app_state = AppState {
+        instances,
+        config: Arc::new(config),
+    };
 
     let app = Router::new()
         .route("/", get(|| async { Redirect::permanent("/metrics") }))
         .route("/metrics", get(metrics_handler))
+        .route("/probe", get(probe_handler))
         .route("/healthz", get(|| async { StatusCode::OK }))
         .with_state(app_state);
 
-    let address = "0.0.0.0:3000".parse()?;
-    let server = axum::Server::bind(&address)
+    let server = axum::Server::bind(&listen_address)
         .serve(app.into_make_service())
         .with_graceful_shutdown(shutdown_signal());
-    tracing::info!("Server listening on http://localhost:3000");
+    tracing::info!("Server listening on http://{}", listen_address);
     server.await?;
     Ok(())
 }
 
+/// Registers the metrics of the instance and spawns its pollers.
+fn start_instance(
+    config: &Config,
+    instance: &InstanceConfig,
+    store: Option<&Store>,
+) -> Result<Instance, Box<dyn std::error::Error>> {
+    let registry = Registry::new_custom(
+        None,
+        Some(HashMap::from([(
+            "instance".to_string(),
+            instance.name.clone(),
+        )])),
+    )?;
+    let metrics = create_metrics(config.journey_labels.clone(), &registry)?;
+    let client = create_client(config, instance, &metrics)?;
+    let snapshot = Arc::new(RwLock::new(None));
+    let poll_failed = Arc::new(AtomicBool::new(false));
+    let span = tracing::info_span!("instance", name = %instance.name);
+
+    // The personal mode only makes sense for instances we have a token for
+    if config.personal && instance.token.is_some() {
+        tokio::spawn(
+            personal::poll_personal_statistics(
+                client.clone(),
+                PersonalMetrics::register(&registry)?,
+                config.statistics_interval,
+            )
+            .instrument(span.clone()),
+        );
+    }
+    if config.events {
+        tokio::spawn(
+            events::poll_events(
+                client.clone(),
+                EventMetrics::register(&registry)?,
+                snapshot.clone(),
+                config.statistics_interval,
+            )
+            .instrument(span.clone()),
+        );
+    }
+    if let Some(size) = config.leaderboard_size {
+        let options = LeaderboardOptions {
+            size,
+            username_hash_key: config.username_hash_key.clone(),
+            friends: instance.token.is_some(),
+        };
+        tokio::spawn(
+            leaderboard::poll_leaderboards(
+                client.clone(),
+                LeaderboardMetrics::register(&registry)?,
+                options,
+                config.statistics_interval,
+            )
+            .instrument(span.clone()),
+        );
+    }
+    let history = match store {
+        Some(store) => {
+            let since = config
+                .history_start
+                .map(|date| Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+            Some(History::register(
+                store.clone(),
+                instance.name.clone(),
+                since,
+                &registry,
+            )?)
+        }
+        None => None,
+    };
+    tokio::spawn(
+        poll_statuses(
+            client,
+            metrics.clone(),
+            snapshot.clone(),
+            poll_failed.clone(),
+            history,
+            config.poll_interval,
+            config.rate_limit_threshold,
+        )
+        .instrument(span),
+    );
+
+    Ok(Instance {
+        registry,
+        metrics,
+        snapshot,
+        poll_failed,
+    })
+}
+
+/// Creates the client of the instance, which records every request it sends in the
+/// given metrics, including retries.
+fn create_client(
+    config: &Config,
+    instance: &InstanceConfig,
+    metrics: &Metrics,
+) -> Result<TraewellingClient, Box<dyn std::error::Error>> {
+    let metrics = metrics.clone();
+    let mut builder = TraewellingClient::builder()
+        .with_base_url(instance.api_url.clone())
+        .with_token(instance.token.clone())
+        .with_request_observer(move |attempt| {
+            metrics.traewelling_requests.inc();
+            metrics
+                .traewelling_request_duration
+                .observe(attempt.duration.as_secs_f64());
+            if let Some(error) = attempt.error {
+                record_request_error(error, &metrics);
+            }
+        });
+    if let Some(timeout) = config.request_timeout {
+        builder = builder.with_timeout(timeout);
+    }
+    if let Some(connect_timeout) = config.connect_timeout {
+        builder = builder.with_connect_timeout(connect_timeout);
+    }
+    if let Some(proxy) = &config.proxy {
+        builder = builder.with_proxy(Proxy::all(proxy.as_str())?);
+    }
+    if let Some(path) = &config.ca_certificate {
+        builder = builder.with_root_certificate(Certificate::from_pem(&std::fs::read(path)?)?);
+    }
+    Ok(builder.build()?)
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
@@ -60,11 +239,8 @@ async fn shutdown_signal() {
 #[derive(Hash, Debug, PartialEq, Eq, Clone)]
 struct CheckinData {
     pub category: String,
-    pub distance: String,
     pub line_name: String,
     pub number: String,
-    pub duration: String,
-    pub speed: String,
     pub user_id: String,
     pub username: String,
     pub origin: String,
@@ -73,144 +249,1176 @@ struct CheckinData {
     pub event_name: Option<String>,
 }
 
+impl From<&Status> for CheckinData {
+    fn from(status: &Status) -> Self {
+        CheckinData {
+            category: status.train.category.clone(),
+            line_name: status.train.line_name.clone(),
+            number: status.train.number.clone(),
+            user_id: status.user.to_string(),
+            username: status.username.clone(),
+            origin: status.train.origin.name.clone(),
+            destination: status.train.destination.name.clone(),
+            event_id: status.event.as_ref().map(|event| event.id.to_string()),
+            event_name: status.event.as_ref().map(|event| event.name.clone()),
+        }
+    }
+}
+
 impl<'a> From<&'a CheckinData> for HashMap<&str, &'a str> {
     fn from(data: &'a CheckinData) -> Self {
         HashMap::from([
             ("category", data.category.as_str()),
-            ("distance", data.distance.as_str()),
             ("line_name", data.line_name.as_str()),
             ("number", data.number.as_str()),
-            ("duration", data.duration.as_str()),
-            ("speed", data.speed.as_str()),
             ("user_id", data.user_id.as_str()),
             ("user_name", data.username.as_str()),
             ("destination", data.destination.as_str()),
             ("origin", data.origin.as_str()),
+            ("event_id", data.event_id.as_deref().unwrap_or_default()),
+            ("event_name", data.event_name.as_deref().unwrap_or_default()),
         ])
     }
 }
 
 #[derive(Clone)]
 struct AppState {
+    instances: Arc<[Instance]>,
+    config: Arc<Config>,
+}
+
+/// Query parameters of the `/probe` endpoint.
+#[derive(Deserialize)]
+struct ProbeParams {
+    /// Base URL of the Traewelling API to probe.
+    target: Url,
+    /// Name of the configured module whose settings are used for the probe.
+    module: Option<String>,
+}
+
+/// A polled Traewelling instance. All of its metrics are registered in its own
+/// registry, which labels them with the name of the instance.
+struct Instance {
+    registry: Registry,
     metrics: Metrics,
+    snapshot: Arc<RwLock<Option<Snapshot>>>,
+    /// Whether the most recent poll of the active statuses failed.
+    poll_failed: Arc<AtomicBool>,
+}
+
+/// The IDs of the most recently seen statuses, forgetting the oldest ones beyond
+/// [`MAX_SEEN_STATUSES`].
+#[derive(Default)]
+struct SeenStatuses {
+    ids: HashSet<i32>,
+    order: VecDeque<i32>,
+}
+
+impl SeenStatuses {
+    /// Remembers the status, returning whether it wasn't seen before.
+    fn insert(&mut self, id: i32) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > MAX_SEEN_STATUSES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Follows the active statuses across polls to tell the journeys which reached their
+/// destination from those which were deleted before.
+#[derive(Default)]
+struct JourneyLifecycle {
+    /// The journeys in progress at the last poll, `None` until the first poll.
+    active: Option<HashMap<i32, ActiveJourney>>,
+}
+
+struct ActiveJourney {
+    category: String,
+    arrival: Option<DateTime<FixedOffset>>,
+}
+
+impl JourneyLifecycle {
+    /// Counts the journeys which started or ended since the last poll.
+    fn update(&mut self, statuses: &[Status], now: DateTime<Utc>, metrics: &Metrics) {
+        let current: HashMap<i32, ActiveJourney> = statuses
+            .iter()
+            .map(|status| {
+                let destination = &status.train.destination;
+                let journey = ActiveJourney {
+                    category: status.train.category.clone(),
+                    arrival: destination.arrival.or(destination.arrival_planned),
+                };
+                (status.id, journey)
+            })
+            .collect();
+        // Journeys which were already active at the first poll didn't start now
+        if let Some(previous) = &self.active {
+            let transition = |category: &str, kind: &str| {
+                metrics
+                    .journey_transitions
+                    .with_label_values(&[category, kind])
+                    .inc()
+            };
+            for (id, journey) in &current {
+                if !previous.contains_key(id) {
+                    transition(&journey.category, "started");
+                }
+            }
+            let tolerance = chrono::Duration::minutes(ARRIVAL_TOLERANCE_MINUTES);
+            for (id, journey) in previous {
+                if current.contains_key(id) {
+                    continue;
+                }
+                let kind = match journey.arrival {
+                    Some(arrival) if arrival <= now + tolerance => "completed",
+                    Some(_) => "vanished",
+                    // Without an arrival time, deleted and completed journeys look alike
+                    None => "unknown",
+                };
+                transition(&journey.category, kind);
+            }
+        }
+        self.active = Some(current);
+    }
+}
+
+/// The most recent result of polling the Traewelling API.
+struct Snapshot {
+    statuses: Vec<Status>,
+    fetched_at: Instant,
 }
 
 #[derive(Clone)]
 struct Metrics {
+    journey_labels: Vec<&'static str>,
     checkins: IntGaugeVec,
+    journeys_by_event: IntGaugeVec,
+    checkins_total: IntCounterVec,
+    distance_total: IntCounterVec,
+    points_total: IntCounter,
+    journey_transitions: IntCounterVec,
     traewelling_requests: IntCounter,
+    traewelling_request_duration: Histogram,
+    traewelling_request_errors: IntCounterVec,
+    traewelling_up: IntGauge,
+    last_successful_fetch: Gauge,
+    scrape_duration: Gauge,
+    rate_limit: IntGauge,
+    rate_limit_remaining: IntGauge,
+    poll_interval: Gauge,
+    snapshot_age: Gauge,
+    average_departure_delay: GaugeVec,
+    average_arrival_delay: GaugeVec,
+    departure_delay: GaugeHistogram,
+    arrival_delay: GaugeHistogram,
+    delayed_journeys: IntGaugeVec,
+    cancelled_journeys: IntGaugeVec,
+    platform_changed_journeys: IntGaugeVec,
+    distance: JourneyAttributeMetrics,
+    duration: JourneyAttributeMetrics,
+    speed: JourneyAttributeMetrics,
+    points: JourneyAttributeMetrics,
 }
 
-fn create_metrics() -> Result<Metrics, prometheus::Error> {
-    let checkins = register_int_gauge_vec!(
+/// Aggregates of a numeric attribute of the current journeys, per category.
+#[derive(Clone)]
+struct JourneyAttributeMetrics {
+    total: GaugeVec,
+    average: GaugeVec,
+    max: GaugeVec,
+    distribution: GaugeHistogram,
+}
+
+impl JourneyAttributeMetrics {
+    fn register(
+        name: &str,
+        help: &str,
+        buckets: &[f64],
+        registry: &Registry,
+    ) -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            total: register_gauge_vec_with_registry!(
+                format!("total_journey_{name}"),
+                format!("Sum of the {help} of current journeys"),
+                &["category"],
+                registry
+            )?,
+            average: register_gauge_vec_with_registry!(
+                format!("average_journey_{name}"),
+                format!("Average {help} of current journeys"),
+                &["category"],
+                registry
+            )?,
+            max: register_gauge_vec_with_registry!(
+                format!("max_journey_{name}"),
+                format!("Maximum {help} of current journeys"),
+                &["category"],
+                registry
+            )?,
+            distribution: GaugeHistogram::register(
+                &format!("journey_{name}"),
+                &format!("Distribution of the {help} of current journeys"),
+                &["category"],
+                buckets,
+                registry,
+            )?,
+        })
+    }
+
+    fn reset(&self) {
+        self.total.reset();
+        self.average.reset();
+        self.max.reset();
+        self.distribution.reset();
+    }
+
+    fn record<'a>(&self, values: impl IntoIterator<Item = (&'a str, f64)>) {
+        // (sum, max, count) per category
+        let mut categories: HashMap<&str, (f64, f64, u32)> = HashMap::new();
+        for (category, value) in values {
+            self.distribution.observe(&[category], value);
+            let (sum, max, count) = categories.entry(category).or_insert((0.0, f64::MIN, 0));
+            *sum += value;
+            *max = f64::max(*max, value);
+            *count += 1;
+        }
+        for (category, (sum, max, count)) in categories {
+            self.total.with_label_values(&[category]).set(sum);
+            self.average
+                .with_label_values(&[category])
+                .set(sum / f64::from(count));
+            self.max.with_label_values(&[category]).set(max);
+        }
+    }
+}
+
+/// The distribution of a value among the current journeys, exported as a gauge per
+/// cumulative `le` bucket. Unlike a histogram it shrinks when journeys end, so it has
+/// to be queried without `rate()`, e.g.
+/// `histogram_quantile(0.9, sum by (le) (departure_delay_seconds_bucket))`.
+#[derive(Clone)]
+struct GaugeHistogram {
+    /// Upper bounds of the buckets as `le` label values, ending with `+Inf`.
+    bounds: Vec<(f64, String)>,
+    buckets: IntGaugeVec,
+}
+
+impl GaugeHistogram {
+    fn register(
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: &[f64],
+        registry: &Registry,
+    ) -> Result<Self, prometheus::Error> {
+        let bounds = buckets
+            .iter()
+            .map(|bound| (*bound, bound.to_string()))
+            .chain([(f64::INFINITY, "+Inf".to_string())])
+            .collect();
+        let mut labels = labels.to_vec();
+        labels.push("le");
+        Ok(Self {
+            bounds,
+            buckets: register_int_gauge_vec_with_registry!(
+                format!("{name}_bucket"),
+                help,
+                &labels,
+                registry
+            )?,
+        })
+    }
+
+    fn reset(&self) {
+        self.buckets.reset();
+    }
+
+    /// Adds the value to every bucket it falls into, creating the others with 0 so
+    /// that the distribution is complete.
+    fn observe(&self, labels: &[&str], value: f64) {
+        let mut values = labels.to_vec();
+        for (bound, le) in &self.bounds {
+            values.push(le);
+            self.buckets
+                .with_label_values(&values)
+                .add(i64::from(value <= *bound));
+            values.pop();
+        }
+    }
+}
+
+fn create_metrics(
+    journey_labels: Vec<&'static str>,
+    registry: &Registry,
+) -> Result<Metrics, prometheus::Error> {
+    let checkins = register_int_gauge_vec_with_registry!(
         "journeys",
         "Current Journeys",
-        &[
-            "category",
-            "distance",
-            "line_name",
-            "number",
-            "duration",
-            "speed",
-            "user_id",
-            "user_name",
-            "origin",
-            "destination",
-        ]
+        &journey_labels,
+        registry
+    )?;
+    let journeys_by_event = register_int_gauge_vec_with_registry!(
+        "journeys_by_event",
+        "Current Journeys checked in to an event",
+        &["event_id", "event_name"],
+        registry
+    )?;
+    let checkins_total = register_int_counter_vec_with_registry!(
+        "checkins_total",
+        "Check-ins observed since the exporter started",
+        &["category"],
+        registry
+    )?;
+    let distance_total = register_int_counter_vec_with_registry!(
+        "distance_meters_total",
+        "Distance of the check-ins observed since the exporter started",
+        &["category"],
+        registry
+    )?;
+    let points_total = register_int_counter_with_registry!(
+        opts!(
+            "points_total",
+            "Points of the check-ins observed since the exporter started"
+        ),
+        registry
+    )?;
+    let journey_transitions = register_int_counter_vec_with_registry!(
+        "journey_transitions_total",
+        "Journeys which started, completed, vanished before their arrival or ended without a known arrival",
+        &["category", "transition"],
+        registry
+    )?;
+    let traewelling_requests = register_int_counter_with_registry!(
+        opts!(
+            "traewelling_requests",
+            "HTTP Requests sent to Traewelling API"
+        ),
+        registry
     )?;
-    let traewelling_requests = register_int_counter!(opts!(
-        "traewelling_requests",
-        "HTTP Requests sent to Traewelling API"
-    ))?;
+    let traewelling_request_duration = register_histogram_with_registry!(
+        "traewelling_request_duration_seconds",
+        "Duration of HTTP Requests sent to Traewelling API",
+        registry
+    )?;
+    let traewelling_request_errors = register_int_counter_vec_with_registry!(
+        "traewelling_request_errors",
+        "Failed HTTP Requests sent to Traewelling API",
+        &["kind", "status_code"],
+        registry
+    )?;
+    let traewelling_up = register_int_gauge_with_registry!(
+        opts!(
+            "traewelling_up",
+            "Whether the last poll of Traewelling API succeeded"
+        ),
+        registry
+    )?;
+    let last_successful_fetch = register_gauge_with_registry!(
+        opts!(
+            "last_successful_fetch_timestamp_seconds",
+            "Unix timestamp of the last successful poll of Traewelling API"
+        ),
+        registry
+    )?;
+    let scrape_duration = register_gauge_with_registry!(
+        opts!(
+            "traewelling_scrape_duration_seconds",
+            "Duration of the last poll of all active statuses from Traewelling API"
+        ),
+        registry
+    )?;
+    let rate_limit = register_int_gauge_with_registry!(
+        opts!(
+            "traewelling_rate_limit",
+            "Requests allowed per rate limit window of Traewelling API"
+        ),
+        registry
+    )?;
+    let rate_limit_remaining = register_int_gauge_with_registry!(
+        opts!(
+            "traewelling_rate_limit_remaining",
+            "Requests left in the current rate limit window of Traewelling API"
+        ),
+        registry
+    )?;
+    let poll_interval = register_gauge_with_registry!(
+        opts!(
+            "poll_interval_seconds",
+            "Current interval between two polls of Traewelling API"
+        ),
+        registry
+    )?;
+    let snapshot_age = register_gauge_with_registry!(
+        opts!(
+            "snapshot_age_seconds",
+            "Seconds since the served journeys were fetched from Traewelling"
+        ),
+        registry
+    )?;
+    let average_departure_delay = register_gauge_vec_with_registry!(
+        "average_departure_delay_seconds",
+        "Average departure delay at the origin of current journeys",
+        &["category", "line_name"],
+        registry
+    )?;
+    let average_arrival_delay = register_gauge_vec_with_registry!(
+        "average_arrival_delay_seconds",
+        "Average arrival delay at the destination of current journeys",
+        &["category", "line_name"],
+        registry
+    )?;
+    let departure_delay = GaugeHistogram::register(
+        "departure_delay_seconds",
+        "Departure delay at the origin of current journeys",
+        &["category", "line_name"],
+        &DELAY_BUCKETS,
+        registry,
+    )?;
+    let arrival_delay = GaugeHistogram::register(
+        "arrival_delay_seconds",
+        "Arrival delay at the destination of current journeys",
+        &["category", "line_name"],
+        &DELAY_BUCKETS,
+        registry,
+    )?;
+    let delayed_journeys = register_int_gauge_vec_with_registry!(
+        "delayed_journeys",
+        "Current Journeys with a delayed departure or arrival",
+        &["category", "line_name"],
+        registry
+    )?;
+    let cancelled_journeys = register_int_gauge_vec_with_registry!(
+        "cancelled_journeys",
+        "Current Journeys whose origin or destination stop is cancelled",
+        &["category", "station"],
+        registry
+    )?;
+    let platform_changed_journeys = register_int_gauge_vec_with_registry!(
+        "platform_changed_journeys",
+        "Current Journeys departing or arriving at a different platform than planned",
+        &["category", "station"],
+        registry
+    )?;
+    let distance = JourneyAttributeMetrics::register(
+        "distance_meters",
+        "distance",
+        &DISTANCE_BUCKETS,
+        registry,
+    )?;
+    let duration = JourneyAttributeMetrics::register(
+        "duration_seconds",
+        "duration",
+        &DURATION_BUCKETS,
+        registry,
+    )?;
+    let speed =
+        JourneyAttributeMetrics::register("speed_kmh", "average speed", &SPEED_BUCKETS, registry)?;
+    let points = JourneyAttributeMetrics::register("points", "points", &POINTS_BUCKETS, registry)?;
     Ok(Metrics {
+        journey_labels,
         checkins,
+        journeys_by_event,
+        checkins_total,
+        distance_total,
+        points_total,
+        journey_transitions,
         traewelling_requests,
+        traewelling_request_duration,
+        traewelling_request_errors,
+        traewelling_up,
+        last_successful_fetch,
+        scrape_duration,
+        rate_limit,
+        rate_limit_remaining,
+        poll_interval,
+        snapshot_age,
+        average_departure_delay,
+        average_arrival_delay,
+        departure_delay,
+        arrival_delay,
+        delayed_journeys,
+        cancelled_journeys,
+        platform_changed_journeys,
+        distance,
+        duration,
+        speed,
+        points,
     })
 }
 
-async fn metrics_handler<'a>(
-    State(AppState { metrics }): State<AppState>,
-) -> Result<String, String> {
-    let Ok(data) = fetch_metrics(&metrics, "metrics").await else {
-        return Err("Failed to fetch journeys".to_string());
+/// Serves the metrics of all instances. Responds with 503 once none of them can serve
+/// journeys because their last poll failed, but not while the first polls are still
+/// running. Prometheus discards the body of such a scrape, so the exporter's own
+/// metrics like `traewelling_up` are missing then and `up` of the scrape is 0.
+async fn metrics_handler(
+    State(AppState { instances, config }): State<AppState>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let mut families = Vec::new();
+    let mut broken = true;
+    for instance in instances.iter() {
+        let (metrics, fresh) = instance.gather(config.max_snapshot_age).await;
+        families.extend(metrics);
+        broken &= !fresh && instance.poll_failed.load(Ordering::Relaxed);
+    }
+    let status = if broken {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
     };
-    record_metrics(data, &metrics);
 
     let mut text = String::new();
     let encoder = TextEncoder::new();
     {
-        let metrics = Registry::default().gather();
-        text += &encoder.encode_to_string(&metrics).unwrap();
+        let metrics = merge_metric_families(families);
+        text += &encoder.encode_to_string(&metrics).map_err(encode_error)?;
         text += "\n\n";
     }
     let metrics = prometheus::gather();
-    text += &encoder.encode_to_string(&metrics).unwrap();
-    Ok(text)
-}
-
-#[cached(
-    time = 2,
-    sync_writes = true,
-    key = "String",
-    result = true,
-    convert = r#"{String::from(_cache_key)}"#
-)]
-async fn fetch_metrics(
-    metrics: &Metrics,
-    _cache_key: &str,
-) -> Result<Vec<(CheckinData, usize)>, ()> {
-    let checkins = match CLIENT.statuses().get_active_statuses().await {
-        Ok(data) => {
-            metrics.traewelling_requests.inc();
-            data.data
+    text += &encoder.encode_to_string(&metrics).map_err(encode_error)?;
+    Ok((status, text))
+}
+
+/// Polls the active statuses of the given target once, in the style of the blackbox
+/// exporter, and exports them in a registry of its own.
+async fn probe_handler(
+    State(AppState { config, .. }): State<AppState>,
+    Query(ProbeParams { target, module }): Query<ProbeParams>,
+) -> Result<String, (StatusCode, String)> {
+    let token = match module {
+        Some(module) => match config.modules.get(&module) {
+            Some(module) if module.allows(&target) => module.token.clone(),
+            Some(_) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Module {module} may not probe {target}"),
+                ))
+            }
+            None => return Err((StatusCode::BAD_REQUEST, format!("Unknown module: {module}"))),
+        },
+        None => None,
+    };
+    let instance = InstanceConfig {
+        name: target.to_string(),
+        api_url: target,
+        token,
+    };
+    let registry = Registry::new();
+    let metrics = create_metrics(config.journey_labels.clone(), &registry).map_err(|e| {
+        tracing::error!("Failed to register probe metrics: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to register metrics".to_string(),
+        )
+    })?;
+    let client = create_client(&config, &instance, &metrics).map_err(|e| {
+        tracing::error!("Failed to create client for {}: {}", instance.api_url, e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create client".to_string(),
+        )
+    })?;
+
+    let started = Instant::now();
+    let result = fetch_statuses(&client).await;
+    metrics.scrape_duration.set(started.elapsed().as_secs_f64());
+    match result {
+        Ok(statuses) => {
+            metrics.traewelling_up.set(1);
+            metrics
+                .last_successful_fetch
+                .set(Utc::now().timestamp() as f64);
+            record_metrics(&statuses, &metrics);
+        }
+        Err(()) => metrics.traewelling_up.set(0),
+    }
+    if let Some(rate_limit) = client.rate_limit() {
+        metrics.rate_limit.set(rate_limit.limit.into());
+        metrics
+            .rate_limit_remaining
+            .set(rate_limit.remaining.into());
+    }
+
+    TextEncoder::new()
+        .encode_to_string(&registry.gather())
+        .map_err(encode_error)
+}
+
+fn encode_error(e: prometheus::Error) -> (StatusCode, String) {
+    tracing::error!("Failed to encode metrics: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to encode metrics".to_string(),
+    )
+}
+
+impl Instance {
+    /// Gathers the metrics of the instance, without the journeys once the snapshot is
+    /// too old, and tells whether the snapshot is fresh. The journeys are recorded by
+    /// [`poll_statuses`] whenever it replaces the snapshot, so holding the lock keeps us
+    /// from gathering them half updated.
+    async fn gather(&self, max_snapshot_age: Duration) -> (Vec<MetricFamily>, bool) {
+        let snapshot = self.snapshot.read().await;
+        let fresh = match snapshot.as_ref() {
+            Some(snapshot) => {
+                let age = snapshot.fetched_at.elapsed();
+                self.metrics.snapshot_age.set(age.as_secs_f64());
+                if age > max_snapshot_age {
+                    // Rather expose no journeys than outdated ones, the exporter's own
+                    // metrics are still served.
+                    reset_journey_metrics(&self.metrics);
+                }
+                age <= max_snapshot_age
+            }
+            None => {
+                tracing::debug!("No journeys fetched yet");
+                false
+            }
+        };
+        (self.registry.gather(), fresh)
+    }
+}
+
+/// Combines the families of the same metric gathered from the registries of several
+/// instances, as a family may only appear once in the exposition format.
+fn merge_metric_families(families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+    let mut merged: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for mut family in families {
+        match merged.entry(family.get_name().to_string()) {
+            Entry::Occupied(mut entry) => {
+                for metric in family.take_metric() {
+                    entry.get_mut().mut_metric().push(metric);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(family);
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+async fn poll_statuses(
+    client: TraewellingClient,
+    metrics: Metrics,
+    snapshot: Arc<RwLock<Option<Snapshot>>>,
+    poll_failed: Arc<AtomicBool>,
+    history: Option<History>,
+    poll_interval: Duration,
+    rate_limit_threshold: u32,
+) {
+    let mut slowdown = 1;
+    let mut seen = None;
+    let mut lifecycle = JourneyLifecycle::default();
+    loop {
+        let started = Instant::now();
+        let result = fetch_statuses(&client).await;
+        metrics.scrape_duration.set(started.elapsed().as_secs_f64());
+        poll_failed.store(result.is_err(), Ordering::Relaxed);
+        match result {
+            Ok(statuses) => {
+                metrics.traewelling_up.set(1);
+                metrics
+                    .last_successful_fetch
+                    .set(Utc::now().timestamp() as f64);
+                record_new_checkins(&statuses, &mut seen, &metrics);
+                lifecycle.update(&statuses, Utc::now(), &metrics);
+                if let Some(history) = &history {
+                    history.record(&statuses);
+                }
+                // Record under the write lock, so scrapes never see the journey
+                // metrics half updated
+                let mut snapshot = snapshot.write().await;
+                record_metrics(&statuses, &metrics);
+                *snapshot = Some(Snapshot {
+                    statuses,
+                    fetched_at: Instant::now(),
+                });
+            }
+            Err(()) => metrics.traewelling_up.set(0),
+        }
+
+        // Back off while we are about to exhaust the rate limit of our token
+        if let Some(rate_limit) = client.rate_limit() {
+            metrics.rate_limit.set(rate_limit.limit.into());
+            metrics
+                .rate_limit_remaining
+                .set(rate_limit.remaining.into());
+            slowdown = if rate_limit.remaining < rate_limit_threshold {
+                (slowdown * 2).min(MAX_POLL_SLOWDOWN)
+            } else {
+                1
+            };
+        }
+        let delay = poll_interval * slowdown;
+        metrics.poll_interval.set(delay.as_secs_f64());
+        tokio::time::sleep_until((started + delay).into()).await;
+    }
+}
+
+/// Calls `poll` every `poll_interval`, logging its errors. Ticks missed while
+/// `poll` is still running are delayed instead of caught up on.
+async fn poll_every<F, Fut>(poll_interval: Duration, name: &str, mut poll: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), traewelling_exporter::Error>>,
+{
+    let mut interval = tokio::time::interval(poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = poll().await {
+            tracing::error!("Failed to fetch {}: {}", name, e);
+        }
+    }
+}
+
+/// Fetches all active statuses, each once. The requests are recorded by the client,
+/// see [`create_client`].
+async fn fetch_statuses(client: &TraewellingClient) -> Result<Vec<Status>, ()> {
+    match client.statuses().get_all_active_statuses().await {
+        Ok(statuses) => {
+            tracing::trace!("Observing {} checkins", statuses.len());
+            Ok(statuses)
         }
         Err(e) => {
-            metrics.traewelling_requests.inc();
             tracing::error!("Traewelling Request failed: {}", e);
-            return Err(());
+            Err(())
         }
+    }
+}
+
+fn record_request_error(error: &traewelling_exporter::Error, metrics: &Metrics) {
+    let status_code = error.status();
+    let kind = if error.is_decode() {
+        "decode"
+    } else if status_code.is_some() {
+        "http_status"
+    } else {
+        "network"
     };
-    tracing::trace!("Observing {} checkins", checkins.len());
-    let checkins = checkins
-        .into_iter()
-        .map(|checkin| CheckinData {
-            category: checkin.train.category,
-            line_name: checkin.train.line_name,
-            distance: checkin.train.distance.to_string(),
-            duration: checkin.train.duration.to_string(),
-            number: checkin.train.number,
-            speed: checkin.train.speed.to_string(),
-            user_id: checkin.user.to_string(),
-            username: checkin.username,
-            origin: checkin.train.origin.name,
-            destination: checkin.train.destination.name,
-            event_id: checkin.event.as_ref().map(|event| event.id.to_string()),
-            event_name: checkin.event.map(|event| event.name),
-        })
-        .group_by(|data| {
-            let mut hasher = DefaultHasher::new();
-            data.hash(&mut hasher);
-            hasher.finish()
-        })
-        .into_iter()
-        .map(|(_, group)| {
-            let data: Vec<CheckinData> = group.collect();
-            let length = data.len();
-            let first = data.into_iter().next().unwrap();
-            (first, length)
-        })
-        .collect();
-    Ok(checkins)
+    let status_code = status_code.map(|code| code.as_str().to_string());
+    metrics
+        .traewelling_request_errors
+        .with_label_values(&[kind, status_code.as_deref().unwrap_or_default()])
+        .inc();
+}
+
+/// Increments the check-in counters once for every status which wasn't seen before.
+/// `seen` is `None` until the first poll, whose statuses are only remembered.
+fn record_new_checkins(statuses: &[Status], seen: &mut Option<SeenStatuses>, metrics: &Metrics) {
+    let Some(known) = seen else {
+        // The check-ins active at the first poll may have been counted before a restart
+        let mut baseline = SeenStatuses::default();
+        for status in statuses {
+            baseline.insert(status.id);
+        }
+        *seen = Some(baseline);
+        return;
+    };
+    for status in statuses.iter().filter(|status| known.insert(status.id)) {
+        let category = status.train.category.as_str();
+        metrics.checkins_total.with_label_values(&[category]).inc();
+        metrics
+            .distance_total
+            .with_label_values(&[category])
+            .inc_by(u64::try_from(status.train.distance).unwrap_or_default());
+        metrics
+            .points_total
+            .inc_by(u64::try_from(status.train.points).unwrap_or_default());
+    }
+}
+
+/// Counts the statuses per distinct [`CheckinData`], regardless of their order.
+fn aggregate_checkins(statuses: &[Status]) -> HashMap<CheckinData, usize> {
+    let mut checkins = HashMap::new();
+    for status in statuses {
+        *checkins.entry(CheckinData::from(status)).or_default() += 1;
+    }
+    checkins
+}
+
+/// Sums up the checkins by the values of the given labels only.
+fn aggregate_journeys<'a>(
+    checkins: &'a HashMap<CheckinData, usize>,
+    journey_labels: &[&str],
+) -> HashMap<Vec<&'a str>, usize> {
+    let mut journeys = HashMap::new();
+    for (checkin, amount) in checkins {
+        let labels: HashMap<&str, &str> = checkin.into();
+        let values = journey_labels.iter().map(|label| labels[label]).collect();
+        *journeys.entry(values).or_default() += amount;
+    }
+    journeys
 }
 
-fn record_metrics(data: Vec<(CheckinData, usize)>, metrics: &Metrics) {
+/// Removes every series derived from the journeys.
+fn reset_journey_metrics(metrics: &Metrics) {
     metrics.checkins.reset();
-    for (ref checkin, amount) in data {
-        let map = checkin.into();
+    metrics.journeys_by_event.reset();
+    metrics.average_departure_delay.reset();
+    metrics.average_arrival_delay.reset();
+    metrics.departure_delay.reset();
+    metrics.arrival_delay.reset();
+    metrics.delayed_journeys.reset();
+    metrics.cancelled_journeys.reset();
+    metrics.platform_changed_journeys.reset();
+    metrics.distance.reset();
+    metrics.duration.reset();
+    metrics.speed.reset();
+    metrics.points.reset();
+}
+
+fn record_metrics(statuses: &[Status], metrics: &Metrics) {
+    reset_journey_metrics(metrics);
+    let checkins = aggregate_checkins(statuses);
+    for (values, amount) in aggregate_journeys(&checkins, &metrics.journey_labels) {
         metrics
             .checkins
-            .get_metric_with(&map)
-            .unwrap()
+            .with_label_values(&values)
             .set(amount as i64);
     }
+    for event in statuses.iter().filter_map(|status| status.event.as_ref()) {
+        metrics
+            .journeys_by_event
+            .with_label_values(&[event.id.to_string().as_str(), event.name.as_str()])
+            .inc();
+    }
+    record_delays(statuses, metrics);
+    record_disruptions(statuses, metrics);
+    record_journey_attributes(statuses, metrics);
+}
+
+fn record_journey_attributes(statuses: &[Status], metrics: &Metrics) {
+    let trains = || statuses.iter().map(|status| &status.train);
+    metrics
+        .distance
+        .record(trains().map(|train| (train.category.as_str(), f64::from(train.distance))));
+    metrics
+        .duration
+        .record(trains().map(|train| (train.category.as_str(), train.duration_seconds() as f64)));
+    metrics
+        .speed
+        .record(trains().map(|train| (train.category.as_str(), train.speed)));
+    metrics
+        .points
+        .record(trains().map(|train| (train.category.as_str(), f64::from(train.points))));
+}
+
+/// Difference between the real and the planned time in seconds, if both are known.
+fn delay_seconds(
+    planned: Option<DateTime<FixedOffset>>,
+    real: Option<DateTime<FixedOffset>>,
+) -> Option<f64> {
+    Some(real?.signed_duration_since(planned?).num_seconds() as f64)
+}
+
+fn record_delays(statuses: &[Status], metrics: &Metrics) {
+    let mut departure_delays: HashMap<[&str; 2], (f64, u32)> = HashMap::new();
+    let mut arrival_delays: HashMap<[&str; 2], (f64, u32)> = HashMap::new();
+    for status in statuses {
+        let train = &status.train;
+        let labels = [train.category.as_str(), train.line_name.as_str()];
+        if let Some(delay) =
+            delay_seconds(train.origin.departure_planned, train.origin.departure_real)
+        {
+            metrics.departure_delay.observe(&labels, delay);
+            let (sum, count) = departure_delays.entry(labels).or_default();
+            *sum += delay;
+            *count += 1;
+        }
+        if let Some(delay) = delay_seconds(
+            train.destination.arrival_planned,
+            train.destination.arrival_real,
+        ) {
+            metrics.arrival_delay.observe(&labels, delay);
+            let (sum, count) = arrival_delays.entry(labels).or_default();
+            *sum += delay;
+            *count += 1;
+        }
+        if train.origin.is_departure_delayed || train.destination.is_arrival_delayed {
+            metrics.delayed_journeys.with_label_values(&labels).inc();
+        }
+    }
+    for (labels, (sum, count)) in departure_delays {
+        metrics
+            .average_departure_delay
+            .with_label_values(&labels)
+            .set(sum / f64::from(count));
+    }
+    for (labels, (sum, count)) in arrival_delays {
+        metrics
+            .average_arrival_delay
+            .with_label_values(&labels)
+            .set(sum / f64::from(count));
+    }
+}
+
+/// Whether the real platform is known and differs from the planned one.
+fn platform_changed(planned: Option<&str>, real: Option<&str>) -> bool {
+    matches!((planned, real), (Some(planned), Some(real)) if planned != real)
+}
+
+fn record_disruptions(statuses: &[Status], metrics: &Metrics) {
+    for status in statuses {
+        let train = &status.train;
+        let (origin, destination) = (&train.origin, &train.destination);
+        for stop in [origin, destination] {
+            if stop.cancelled {
+                metrics
+                    .cancelled_journeys
+                    .with_label_values(&[train.category.as_str(), stop.name.as_str()])
+                    .inc();
+            }
+        }
+        if platform_changed(
+            origin.departure_platform_planned.as_deref(),
+            origin.platform.as_deref(),
+        ) {
+            metrics
+                .platform_changed_journeys
+                .with_label_values(&[train.category.as_str(), origin.name.as_str()])
+                .inc();
+        }
+        if platform_changed(
+            destination.arrival_platform_planned.as_deref(),
+            destination.arrival_platform_real.as_deref(),
+        ) {
+            metrics
+                .platform_changed_journeys
+                .with_label_values(&[train.category.as_str(), destination.name.as_str()])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(id: i32, category: &str, line_name: &str, username: &str) -> Status {
+        let stopover = |name: &str| {
+            serde_json::json!({
+                "id": 1,
+                "name": name,
+                "evaIdentifier": 8000001,
+                "isArrivalDelayed": false,
+                "isDepartureDelayed": false,
+                "cancelled": false,
+            })
+        };
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "user": username.len(),
+            "username": username,
+            "business": 0,
+            "createdAt": "2023-01-15T12:00:00+01:00",
+            "train": {
+                "trip": 1,
+                "hafasId": "1|2|3",
+                "category": category,
+                "number": line_name,
+                "lineName": line_name,
+                "distance": 1000,
+                "points": 1,
+                "duration": 10,
+                "speed": 60.0,
+                "origin": stopover("Hamburg Hbf"),
+                "destination": stopover("Berlin Hbf"),
+            },
+        }))
+        .unwrap()
+    }
+
+    /// The same check-ins in three orders, with the duplicates never adjacent.
+    fn shuffled_statuses() -> [Vec<Status>; 3] {
+        let statuses = || {
+            vec![
+                status(1, "suburban", "S1", "alice"),
+                status(2, "suburban", "S1", "bob"),
+                status(3, "nationalExpress", "ICE 1", "alice"),
+                status(4, "suburban", "S1", "alice"),
+                status(5, "suburban", "S2", "alice"),
+            ]
+        };
+        let reorder = |order: [usize; 5]| {
+            let mut statuses = statuses().into_iter().map(Some).collect::<Vec<_>>();
+            order.iter().map(|i| statuses[*i].take().unwrap()).collect()
+        };
+        [
+            statuses(),
+            reorder([4, 0, 2, 1, 3]),
+            reorder([3, 1, 4, 2, 0]),
+        ]
+    }
+
+    #[test]
+    fn aggregate_checkins_sums_shuffled_duplicates() {
+        let [first, second, third] = shuffled_statuses();
+        let checkins = aggregate_checkins(&first);
+        assert_eq!(checkins, aggregate_checkins(&second));
+        assert_eq!(checkins, aggregate_checkins(&third));
+
+        assert_eq!(checkins.len(), 4);
+        assert_eq!(checkins.values().sum::<usize>(), 5);
+        let alice_s1 = CheckinData::from(&status(0, "suburban", "S1", "alice"));
+        assert_eq!(checkins[&alice_s1], 2);
+    }
+
+    #[test]
+    fn aggregate_journeys_sums_onto_low_cardinality_labels() {
+        let labels = ["category", "line_name"];
+        for statuses in shuffled_statuses() {
+            let checkins = aggregate_checkins(&statuses);
+            let journeys = aggregate_journeys(&checkins, &labels);
+            assert_eq!(
+                journeys,
+                HashMap::from([
+                    (vec!["suburban", "S1"], 3),
+                    (vec!["nationalExpress", "ICE 1"], 1),
+                    (vec!["suburban", "S2"], 1),
+                ])
+            );
+        }
+    }
+
+    #[test]
+    fn aggregate_journeys_sums_onto_category() {
+        for statuses in shuffled_statuses() {
+            let checkins = aggregate_checkins(&statuses);
+            let journeys = aggregate_journeys(&checkins, &["category"]);
+            assert_eq!(
+                journeys,
+                HashMap::from([(vec!["suburban"], 4), (vec!["nationalExpress"], 1)])
+            );
+        }
+    }
+
+    fn metrics() -> Metrics {
+        create_metrics(vec!["category"], &Registry::new()).unwrap()
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 1, 15, 12, 0, 0).unwrap()
+    }
+
+    /// A status arriving the given minutes after [`now`], if at all.
+    fn arriving(id: i32, minutes: Option<i64>) -> Status {
+        let mut status = status(id, "suburban", "S1", "alice");
+        status.train.destination.arrival_planned =
+            minutes.map(|minutes| (now() + chrono::Duration::minutes(minutes)).into());
+        status
+    }
+
+    fn transitions(metrics: &Metrics, transition: &str) -> u64 {
+        metrics
+            .journey_transitions
+            .with_label_values(&["suburban", transition])
+            .get()
+    }
+
+    #[test]
+    fn journey_lifecycle_takes_first_poll_as_baseline() {
+        let metrics = metrics();
+        let mut lifecycle = JourneyLifecycle::default();
+        lifecycle.update(&[arriving(1, Some(30))], now(), &metrics);
+        assert_eq!(transitions(&metrics, "started"), 0);
+
+        lifecycle.update(
+            &[arriving(1, Some(30)), arriving(2, Some(30))],
+            now(),
+            &metrics,
+        );
+        assert_eq!(transitions(&metrics, "started"), 1);
+    }
+
+    #[test]
+    fn journey_lifecycle_classifies_ended_journeys() {
+        let metrics = metrics();
+        let mut lifecycle = JourneyLifecycle::default();
+        let statuses = [
+            arriving(1, Some(-10)),
+            arriving(2, Some(ARRIVAL_TOLERANCE_MINUTES - 1)),
+            arriving(3, Some(60)),
+            arriving(4, None),
+        ];
+        lifecycle.update(&statuses, now(), &metrics);
+        lifecycle.update(&[], now(), &metrics);
+        assert_eq!(transitions(&metrics, "completed"), 2);
+        assert_eq!(transitions(&metrics, "vanished"), 1);
+        assert_eq!(transitions(&metrics, "unknown"), 1);
+        assert_eq!(transitions(&metrics, "started"), 0);
+    }
+
+    #[test]
+    fn record_new_checkins_counts_every_status_once() {
+        let metrics = metrics();
+        let mut seen = None;
+        let poll = |ids: &[i32]| -> Vec<Status> {
+            ids.iter()
+                .map(|id| status(*id, "suburban", "S1", "alice"))
+                .collect()
+        };
+        let totals = || {
+            [
+                metrics
+                    .checkins_total
+                    .with_label_values(&["suburban"])
+                    .get(),
+                metrics
+                    .distance_total
+                    .with_label_values(&["suburban"])
+                    .get(),
+                metrics.points_total.get(),
+            ]
+        };
+
+        record_new_checkins(&poll(&[1, 2]), &mut seen, &metrics);
+        assert_eq!(totals(), [0, 0, 0]);
+        record_new_checkins(&poll(&[2, 3]), &mut seen, &metrics);
+        assert_eq!(totals(), [1, 1000, 1]);
+        record_new_checkins(&poll(&[1, 2, 3, 4, 5]), &mut seen, &metrics);
+        assert_eq!(totals(), [3, 3000, 3]);
+        record_new_checkins(&poll(&[3, 4, 5]), &mut seen, &metrics);
+        assert_eq!(totals(), [3, 3000, 3]);
+    }
+
+    #[test]
+    fn seen_statuses_forget_the_oldest() {
+        let mut seen = SeenStatuses::default();
+        let max = i32::try_from(MAX_SEEN_STATUSES).unwrap();
+        for id in 0..=max {
+            assert!(seen.insert(id));
+        }
+        assert_eq!(seen.ids.len(), MAX_SEEN_STATUSES);
+        assert!(!seen.insert(max));
+        assert!(!seen.insert(1));
+        // Only the oldest ID was forgotten, and remembering it again evicts the next
+        assert!(seen.insert(0));
+        assert!(seen.insert(1));
+        assert_eq!(seen.ids.len(), MAX_SEEN_STATUSES);
+    }
+
+    #[test]
+    fn gauge_histogram_holds_the_current_distribution() {
+        let registry = Registry::new();
+        let histogram =
+            GaugeHistogram::register("test", "Test", &["category"], &[1.0, 10.0], &registry)
+                .unwrap();
+        let bucket = |le: &str| histogram.buckets.with_label_values(&["bus", le]).get();
+
+        histogram.observe(&["bus"], 5.0);
+        histogram.observe(&["bus"], 50.0);
+        assert_eq!([bucket("1"), bucket("10"), bucket("+Inf")], [0, 1, 2]);
+
+        // The next poll replaces the distribution instead of adding to it
+        histogram.reset();
+        histogram.observe(&["bus"], 0.5);
+        assert_eq!([bucket("1"), bucket("10"), bucket("+Inf")], [1, 1, 1]);
+    }
 }
@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use prometheus::{
+    register_gauge_vec_with_registry, register_int_gauge_vec_with_registry, GaugeVec, IntGaugeVec,
+    Registry,
+};
+use traewelling_exporter::traewelling::client::{StatisticsEntry, TraewellingClient};
+
+use crate::poll_every;
+
+/// Metrics about the lifetime travels of the user the token belongs to.
+#[derive(Clone)]
+pub struct PersonalMetrics {
+    distance: GaugeVec,
+    duration: GaugeVec,
+    points: IntGaugeVec,
+    checkins: IntGaugeVec,
+    checkins_by_category: IntGaugeVec,
+    duration_by_category: GaugeVec,
+    checkins_by_operator: IntGaugeVec,
+    duration_by_operator: GaugeVec,
+}
+
+impl PersonalMetrics {
+    pub fn register(registry: &Registry) -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            distance: register_gauge_vec_with_registry!(
+                "user_distance_meters",
+                "Total distance travelled by the user",
+                &["username"],
+                registry
+            )?,
+            duration: register_gauge_vec_with_registry!(
+                "user_duration_seconds",
+                "Total duration travelled by the user",
+                &["username"],
+                registry
+            )?,
+            points: register_int_gauge_vec_with_registry!(
+                "user_points",
+                "Total points of the user",
+                &["username"],
+                registry
+            )?,
+            checkins: register_int_gauge_vec_with_registry!(
+                "user_checkins",
+                "Total check-ins of the user",
+                &["username"],
+                registry
+            )?,
+            checkins_by_category: register_int_gauge_vec_with_registry!(
+                "user_checkins_by_category",
+                "Total check-ins of the user per category",
+                &["username", "category"],
+                registry
+            )?,
+            duration_by_category: register_gauge_vec_with_registry!(
+                "user_duration_by_category_seconds",
+                "Total duration travelled by the user per category",
+                &["username", "category"],
+                registry
+            )?,
+            checkins_by_operator: register_int_gauge_vec_with_registry!(
+                "user_checkins_by_operator",
+                "Total check-ins of the user per operator",
+                &["username", "operator"],
+                registry
+            )?,
+            duration_by_operator: register_gauge_vec_with_registry!(
+                "user_duration_by_operator_seconds",
+                "Total duration travelled by the user per operator",
+                &["username", "operator"],
+                registry
+            )?,
+        })
+    }
+}
+
+pub async fn poll_personal_statistics(
+    client: TraewellingClient,
+    metrics: PersonalMetrics,
+    poll_interval: Duration,
+) {
+    poll_every(poll_interval, "personal statistics", || {
+        record_personal_statistics(&client, &metrics)
+    })
+    .await
+}
+
+async fn record_personal_statistics(
+    client: &TraewellingClient,
+    metrics: &PersonalMetrics,
+) -> Result<(), traewelling_exporter::Error> {
+    let user = client.users().get_authenticated_user().await?.user;
+    // Traewelling only went online in 2019, so this covers every check-in
+    let since = Utc.with_ymd_and_hms(2019, 1, 1, 0, 0, 0).unwrap();
+    let statistics = client
+        .statistics()
+        .get_statistics(since, Utc::now())
+        .await?;
+
+    let username = user.username.as_str();
+    metrics
+        .distance
+        .with_label_values(&[username])
+        .set(user.train_distance as f64);
+    metrics
+        .duration
+        .with_label_values(&[username])
+        .set(user.train_duration_seconds() as f64);
+    metrics
+        .points
+        .with_label_values(&[username])
+        .set(user.points);
+    metrics
+        .checkins
+        .with_label_values(&[username])
+        .set(statistics.categories.iter().map(|entry| entry.count).sum());
+    record_breakdown(
+        username,
+        &statistics.categories,
+        &metrics.checkins_by_category,
+        &metrics.duration_by_category,
+    );
+    record_breakdown(
+        username,
+        &statistics.operators,
+        &metrics.checkins_by_operator,
+        &metrics.duration_by_operator,
+    );
+    Ok(())
+}
+
+fn record_breakdown(
+    username: &str,
+    entries: &[StatisticsEntry],
+    checkins: &IntGaugeVec,
+    duration: &GaugeVec,
+) {
+    checkins.reset();
+    duration.reset();
+    for entry in entries {
+        let labels = [username, entry.name.as_deref().unwrap_or("unknown")];
+        checkins.with_label_values(&labels).set(entry.count);
+        duration
+            .with_label_values(&labels)
+            .set(entry.duration_seconds() as f64);
+    }
+}